@@ -1,6 +1,6 @@
 #!/usr/bin/env bash
 
-password='I X@X@ Nickelback <3'
+export password='I X@X@ Nickelback <3'
 plaintext="Hello World"
 plaintext_filename="plain.txt"
 
@@ -14,13 +14,13 @@ else
     bin="./target/debug/aes-256-cbc";
 fi
 #auth=--key-filename "${key_filename}"
-#auth=--password "${password}"
+#auth=--password-env password
 
 set -e
 for i in $(seq 10); do
     export key_filename="key${i}.yaml";
     export cyphertext_filename="cyphertext${i}.yaml";
-    ${bin} generate --key-filename "${key_filename}" --password "${password}";
+    ${bin} generate --key-filename "${key_filename}" --password-env password;
 done
 
 for i in $(seq 10); do
@@ -32,14 +32,21 @@ for i in $(seq 10); do
 done
 
 
-# every key should decrypt every file since the key is derived
+# the password decrypts every file since the header records the salt,
+# while every key file is refused the files of the other keys
 for i in $(seq 10); do
     for j in $(seq 10); do
         export key_filename="key${j}.yaml";
         export cyphertext_filename="cyphertext${i}.aes";
         echo "${key_filename}: ${cyphertext_filename}"
-        ${bin} decrypt --key-filename "${key_filename}" --input-filename "${cyphertext_filename}" --output-filename "${plaintext_filename}"
-        ${bin} decrypt --password "${password}" --input-filename "${cyphertext_filename}" --output-filename "${plaintext_filename}"
+        if [ "${i}" == "${j}" ]; then
+            ${bin} decrypt --key-filename "${key_filename}" --input-filename "${cyphertext_filename}" --output-filename "${plaintext_filename}"
+        else
+            if ${bin} decrypt --key-filename "${key_filename}" --input-filename "${cyphertext_filename}" --output-filename "${plaintext_filename}"; then
+                exit 1
+            fi
+        fi
+        ${bin} decrypt --password-env password --input-filename "${cyphertext_filename}" --output-filename "${plaintext_filename}"
         test "$(cat $plaintext_filename)" == "${plaintext}"
 
     done
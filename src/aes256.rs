@@ -1,98 +1,1010 @@
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
 use console::style;
+use glob::glob;
+use std::fs;
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use toolz::aes256cbc::b64encode;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use toolz::aes256cbc::archive::EntryKind;
+use toolz::aes256cbc::armor;
+use toolz::aes256cbc::format::{Cipher, Envelope};
+use toolz::aes256cbc::{b64encode, generate_salt, replace_file, shred};
+use toolz::aes256cbc::{DecryptingReader, EncryptingWriter, Error};
 
+use toolz::aes256cbc::keyring::Keyring;
+use toolz::aes256cbc::openssl::{self, OpensslKdf};
+use toolz::aes256cbc::shamir::Share;
+use toolz::aes256cbc::tree::TreeSummary;
 use toolz::aes256cbc::Config;
-use toolz::aes256cbc::Key;
+use toolz::aes256cbc::DEFAULT_CONFIG_PATH;
+use toolz::aes256cbc::{KdfConfig, Key};
 use toolz::core;
+use toolz::progress;
+use zeroize::Zeroizing;
 
-pub fn read_bytes(filename: &str) -> Vec<u8> {
-    let f = File::open(filename).expect("failed to open file");
-    let mut reader = BufReader::new(f);
-    let mut buffer = Vec::new();
-    reader
-        .read_to_end(&mut buffer)
-        .expect("failed to read file");
-    buffer
+/// Reads a secret from the terminal, exiting if there is none
+fn read_secret(prompt: &str) -> Zeroizing<String> {
+    match rpassword::read_password_from_tty(Some(prompt)) {
+        Ok(secret) => Zeroizing::new(secret),
+        Err(error) => {
+            eprintln!(
+                "{}{}",
+                style("cannot read from terminal: ").color256(202),
+                error
+            );
+            std::process::exit(1);
+        }
+    }
 }
-
-pub fn confirm_password() -> Option<String> {
-    let password = rpassword::prompt_password_stderr("Password: ").unwrap();
-    let confirmation = rpassword::prompt_password_stderr("Confirm password: ").unwrap();
+fn confirm_secret(name: &str) -> Option<Zeroizing<String>> {
+    let password = read_secret(&format!("{}: ", name));
+    let confirmation = read_secret(&format!("Confirm {}: ", name.to_lowercase()));
 
     if password != confirmation {
-        eprintln!("{}", style("Password/Confirmation mismatch").color256(202));
+        eprintln!(
+            "{}",
+            style(format!("{}/Confirmation mismatch", name)).color256(202)
+        );
         None
     } else {
         Some(password)
     }
 }
-fn get_password_from_matches(matches: &ArgMatches) -> String {
+pub fn confirm_password() -> Option<Zeroizing<String>> {
+    confirm_secret("Password")
+}
+/// Asks for the new passphrase of a protected key file, exiting when
+/// it is empty or not confirmed
+fn confirm_passphrase() -> Zeroizing<String> {
+    match confirm_secret("Key passphrase") {
+        Some(passphrase) if !passphrase.is_empty() => passphrase,
+        Some(_) => {
+            eprintln!("{}", style("empty passphrase").color256(202));
+            std::process::exit(1);
+        }
+        None => std::process::exit(1),
+    }
+}
+/// The options giving the password of a command, besides `--ask-password`
+const PASSWORD_SOURCES: [&str; 5] = [
+    "password",
+    "password_env",
+    "password_file",
+    "password_fd",
+    "password_command",
+];
+
+/// The `--password-*` options reading the password from elsewhere than
+/// the command line, which would leak it to the shell history and `ps`
+fn password_source_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let sources = [
+        (
+            "password_env",
+            "password-env",
+            "VAR",
+            "read the password from an environment variable",
+        ),
+        (
+            "password_file",
+            "password-file",
+            "PATH",
+            "read the password from the first line of a file",
+        ),
+        (
+            "password_fd",
+            "password-fd",
+            "N",
+            "read the password from an inherited file descriptor",
+        ),
+        (
+            "password_command",
+            "password-command",
+            "COMMAND",
+            "use the output of a shell command as the password",
+        ),
+    ];
+    sources
+        .iter()
+        .map(|&(name, long, value_name, help)| {
+            Arg::with_name(name)
+                .long(long)
+                .value_name(value_name)
+                .help(help)
+                .conflicts_with_all(
+                    &PASSWORD_SOURCES
+                        .iter()
+                        .filter(|&&other| other != name)
+                        .copied()
+                        .collect::<Vec<&str>>(),
+                )
+                .takes_value(true)
+        })
+        .collect()
+}
+
+/// Removes the line ending of a password read from a file or a command
+fn trim_newline(mut password: Zeroizing<String>) -> Zeroizing<String> {
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+    password
+}
+
+/// Reads everything from a file descriptor inherited from the parent
+/// process, like `gpg --passphrase-fd`
+///
+/// The descriptor is left open, as it may be stdin or stdout, which the
+/// command still uses.
+#[cfg(unix)]
+fn read_fd(fd: &str) -> io::Result<Zeroizing<String>> {
+    use std::os::unix::io::FromRawFd;
+    let fd = fd
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a file descriptor"))?;
+    // the file does not own the descriptor, so it must not close it
+    let mut file = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut password = Zeroizing::new(String::new());
+    file.read_to_string(&mut password)?;
+    Ok(password)
+}
+#[cfg(not(unix))]
+fn read_fd(_fd: &str) -> io::Result<Zeroizing<String>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "file descriptors are only supported on unix",
+    ))
+}
+
+/// Runs `command` with `sh -c`, its stderr going to the terminal
+fn read_command(command: &str) -> io::Result<Zeroizing<String>> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    let password = Zeroizing::new(
+        String::from_utf8(output.stdout)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "output is not UTF-8"))?,
+    );
+    if !output.status.success() {
+        return Err(io::Error::other(format!("exited with {}", output.status)));
+    }
+    Ok(password)
+}
+
+fn get_password_from_matches(matches: &ArgMatches) -> Zeroizing<String> {
     let ask_password = matches.is_present("ask_password");
     let password = if ask_password {
         match confirm_password() {
-            Some(password) => password,
-            None => String::from(matches.value_of("password").unwrap_or("")),
+            Some(password) => Ok(password),
+            None => Ok(Zeroizing::new(String::from(
+                matches.value_of("password").unwrap_or(""),
+            ))),
         }
+    } else if let Some(name) = matches.value_of("password_env") {
+        std::env::var(name)
+            .map(Zeroizing::new)
+            .map_err(|error| io::Error::new(io::ErrorKind::NotFound, error))
+    } else if let Some(path) = matches.value_of("password_file") {
+        fs::read_to_string(path).map(|password| trim_newline(Zeroizing::new(password)))
+    } else if let Some(fd) = matches.value_of("password_fd") {
+        read_fd(fd).map(trim_newline)
+    } else if let Some(command) = matches.value_of("password_command") {
+        read_command(command).map(trim_newline)
     } else {
-        String::from(matches.value_of("password").unwrap_or(""))
+        Ok(Zeroizing::new(String::from(
+            matches.value_of("password").unwrap_or(""),
+        )))
     };
-    password
+    match password {
+        Ok(password) => password,
+        Err(error) => {
+            eprintln!("{}{}", style("cannot read password: ").color256(202), error);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn load_key(matches: &ArgMatches, config: &Config) -> Key {
+/// Loads the key file or derives a key from the password with the given
+/// salt, or with the legacy password-derived salt when there is none,
+/// falling back to the default key of the config
+fn load_key(matches: &ArgMatches, config: &Config, salt: Option<&[u8]>) -> Key {
     let password = get_password_from_matches(matches);
     let key_filename = matches.value_of("key_filename").unwrap_or("");
 
     if key_filename.len() > 0 {
-        Key::import(key_filename)
+        import_key(key_filename)
     } else if password.len() > 0 {
-        Key::from_password(&password.as_bytes(), config)
+        match salt {
+            Some(salt) => Key::from_password(password.as_bytes(), salt, config),
+            None => Key::from_legacy_password(password.as_bytes(), config),
+        }
+        .unwrap_or_else(|error| exit_error(error))
+    } else if let Some(ref default_key_path) = config.default_key_path {
+        import_key(&shellexpand::tilde(default_key_path))
     } else {
-        panic!(
+        eprintln!(
             "{}{}{}{}{}",
-            style("either").color256(195),
+            style("either ").color256(195),
             style("--password, --key-filename").color256(49),
             style(" or ").color256(195),
             style("--ask-password").color256(49),
-            style(" is required").color256(195),
+            style(" is required when there is no default key").color256(195),
         );
+        std::process::exit(1);
+    }
+}
+
+/// Loads the key file, or the default key of the config, for the commands
+/// that take no password
+fn load_key_file(matches: &ArgMatches, config: &Config) -> Key {
+    match (matches.value_of("key_filename"), &config.default_key_path) {
+        (Some(key_filename), _) => import_key(key_filename),
+        (None, Some(default_key_path)) => import_key(&shellexpand::tilde(default_key_path)),
+        (None, None) => {
+            eprintln!(
+                "{}{}",
+                style("--key-filename").color256(49),
+                style(" is required when there is no default key").color256(195),
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Whether the command names its key, rather than using the keyring
+fn names_key(matches: &ArgMatches) -> bool {
+    ["key_filename", "ask_password"]
+        .iter()
+        .chain(PASSWORD_SOURCES.iter())
+        .any(|name| matches.is_present(name))
+}
+
+/// Exits with an error that needs no more context
+fn exit_error(error: Error) -> ! {
+    eprintln!("{}", style(error).color256(202));
+    std::process::exit(1);
+}
+
+/// Imports a key file, asking for its passphrase if it is protected
+fn import_key(filename: &str) -> Key {
+    match Key::import(filename) {
+        Ok(key) => key,
+        Err(error) => {
+            eprintln!(
+                "{}{}: {}",
+                style("cannot read key file ").color256(202),
+                style(filename).color256(208),
+                error
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the fingerprint of the key used by a command
+fn print_fingerprint(key: &Key) {
+    let fingerprint = key.fingerprint().unwrap_or_else(|error| exit_error(error));
+    eprintln!(
+        "{}{}",
+        style("key fingerprint: ").color256(44),
+        style(fingerprint).color256(45)
+    );
+}
+
+/// Stands for stdin or stdout in place of a filename
+const STDIO: &str = "-";
+
+/// Opens the input file, or stdin for `-`
+fn open_input(filename: &str) -> Box<dyn Read> {
+    if filename == STDIO {
+        return Box::new(io::stdin());
+    }
+    let file =
+        File::open(filename).unwrap_or_else(|error| exit_failed("open", filename, error.into()));
+    Box::new(BufReader::new(file))
+}
+/// Creates the output file, or writes to stdout for `-`
+fn create_output(filename: &str) -> Box<dyn Write> {
+    if filename == STDIO {
+        return Box::new(BufWriter::new(io::stdout()));
+    }
+    let file = File::create(filename)
+        .unwrap_or_else(|error| exit_failed("create", filename, error.into()));
+    Box::new(BufWriter::new(file))
+}
+/// Writes the output through `write` into a temporary file, which only
+/// replaces the output file once it is complete and synced, so that
+/// nothing is left of a failed run, like unauthenticated plaintext
+///
+/// Stdout and special files such as `/dev/null` are written directly.
+fn write_output<F>(filename: &str, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), Error>,
+{
+    let regular = fs::metadata(filename).map_or(true, |metadata| metadata.is_file());
+    if filename != STDIO && regular {
+        return replace_file(filename, |file| {
+            let mut output = BufWriter::new(file);
+            write(&mut output)?;
+            Ok(output.flush()?)
+        });
+    }
+    let mut output = create_output(filename);
+    write(&mut output)?;
+    Ok(output.flush()?)
+}
+/// Exits if `--in-place` is given with stdin as input
+fn check_in_place(matches: &ArgMatches, input: &str) {
+    if matches.is_present("in_place") && matches.value_of(input) == Some(STDIO) {
+        eprintln!(
+            "{}",
+            style("--in-place needs an input file, not stdin").color256(202)
+        );
+        std::process::exit(1);
+    }
+}
+/// Opens the plaintext file given with `--shred`, so that it can still be
+/// overwritten once `--in-place` replaced it
+fn open_shredded(matches: &ArgMatches, filename: &str) -> Option<File> {
+    if !matches.is_present("shred") {
+        return None;
+    }
+    if filename == STDIO {
+        eprintln!("{}", style("cannot shred stdin").color256(202));
+        std::process::exit(1);
+    }
+    match fs::OpenOptions::new().write(true).open(filename) {
+        Ok(file) => Some(file),
+        Err(error) => {
+            eprintln!(
+                "{}{}: {}",
+                style("cannot shred ").color256(202),
+                style(filename).color256(208),
+                error
+            );
+            std::process::exit(1);
+        }
+    }
+}
+/// Overwrites the plaintext with random bytes, then deletes it unless
+/// `--in-place` already replaced it
+fn shred_plaintext(mut file: File, filename: &str, in_place: bool) {
+    let result = shred(&mut file).and_then(|()| match in_place {
+        true => Ok(()),
+        false => fs::remove_file(filename).map_err(Error::from),
+    });
+    match result {
+        Ok(()) => eprintln!(
+            "{}{}",
+            style("shredded plaintext: ").color256(207),
+            style(filename).color256(205)
+        ),
+        Err(error) => {
+            eprintln!(
+                "{}{}: {}",
+                style("failed to shred ").color256(202),
+                style(filename).color256(208),
+                error
+            );
+            std::process::exit(1);
+        }
     }
 }
 
 fn generate_command(matches: &ArgMatches, config: &Config) {
-    let ask_password = matches.is_present("ask_password");
     // test
     // C-x C-s to save, just like in emacs
 
-    let password = if ask_password {
-        match confirm_password() {
-            Some(password) => password,
-            None => String::from(matches.value_of("password").unwrap_or("")),
-        }
+    let mnemonic = if matches.is_present("ask_mnemonic") {
+        Some(read_secret("Mnemonic: "))
+    } else {
+        matches
+            .value_of("mnemonic")
+            .map(|phrase| Zeroizing::new(String::from(phrase)))
+    };
+    // the password of a mnemonic key is its optional BIP39 passphrase
+    let password = get_password_from_matches(matches);
+    let cipher_name = matches.value_of("cipher").unwrap();
+    let cipher = Cipher::from_name(cipher_name).unwrap_or_else(|| {
+        exit_error(Error::unsupported(format!(
+            "unknown cipher: {}",
+            cipher_name
+        )))
+    });
+    let key = match mnemonic {
+        Some(phrase) => Key::from_mnemonic(&phrase, &password, config),
+        None => Key::from_password(password.as_bytes(), &generate_salt(), config),
+    }
+    .unwrap_or_else(|error| exit_error(error))
+    .with_cipher(cipher);
+    let fingerprint = key.fingerprint().unwrap_or_else(|error| exit_error(error));
+    let key = if matches.is_present("protect") {
+        key.protect(confirm_passphrase().as_bytes(), config)
+            .unwrap_or_else(|error| exit_error(error))
     } else {
-        String::from(matches.value_of("password").unwrap_or(""))
+        key
     };
-    let key = Key::from_password(&password.as_bytes(), config);
 
     let filename = matches.value_of("key_filename").unwrap();
     //let key_yaml = key.to_yaml();
-    let key_path = key.export(filename);
+    let key_path = key
+        .export(filename)
+        .unwrap_or_else(|error| exit_failed("write", filename, error));
     eprintln!(
         "{}{}",
         style("generated key: ").color256(44),
         style(key_path).color256(45)
     );
+    eprintln!(
+        "{}{}",
+        style("key fingerprint: ").color256(44),
+        style(fingerprint).color256(45)
+    );
 }
-fn encrypt_command(matches: &ArgMatches, config: &Config) {
-    let key = load_key(matches, config);
+/// Describes how a key was derived, from its `magic` cycles and `kdf`
+fn describe_kdf(key: &Key) -> String {
+    let cycles = match key.magic {
+        Some(ref magic) if magic.len() == 3 => format!(
+            "{} key, {} salt and {} iv cycles",
+            magic[0], magic[1], magic[2]
+        ),
+        _ => return String::from("none, random key"),
+    };
+    match key.kdf {
+        Some(KdfConfig::Scrypt(scrypt)) => format!(
+            "scrypt with log_n {}, r {} and p {}, {}",
+            scrypt.log_n, scrypt.r, scrypt.p, cycles
+        ),
+        Some(KdfConfig::Argon2id(argon2)) => format!(
+            "argon2id with {} KiB, time {} and parallelism {}, {}",
+            argon2.memory, argon2.time, argon2.parallelism, cycles
+        ),
+        _ => format!("pbkdf2 with {}", cycles),
+    }
+}
+fn key_list_command(config: &Config) {
+    let keyring = Keyring::user();
+    let default_key_path = config
+        .default_key_path
+        .as_ref()
+        .map(|path| shellexpand::tilde(path).to_string());
+    for name in keyring.names().unwrap_or_else(|error| exit_error(error)) {
+        let path = keyring.key_path(&name).unwrap();
+        let marker = if default_key_path.as_deref() == path.to_str() {
+            "*"
+        } else {
+            " "
+        };
+        let description = match keyring.get(&name) {
+            Ok(ref key) if key.is_protected() => format!("{} protected", key.algo),
+            Ok(key) => format!(
+                "{} {}",
+                key.algo,
+                key.fingerprint().unwrap_or_else(|error| error.to_string())
+            ),
+            Err(error) => error.to_string(),
+        };
+        println!(
+            "{} {} {}",
+            style(marker).color256(49),
+            style(name).color256(44),
+            style(description).color256(45)
+        );
+    }
+}
+fn key_add_command(matches: &ArgMatches) {
+    let filename = matches.value_of("key_filename").unwrap();
+    let name = match matches.value_of("name") {
+        Some(name) => String::from(name),
+        None => std::path::Path::new(filename)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+    let path = Keyring::user()
+        .add(&name, filename)
+        .unwrap_or_else(|error| exit_error(error));
+    eprintln!(
+        "{}{}",
+        style("added key to keyring: ").color256(44),
+        style(path.display()).color256(45)
+    );
+}
+fn key_remove_command(matches: &ArgMatches, config: &Config) {
+    let name = matches.value_of("name").unwrap();
+    let path = Keyring::user()
+        .remove(name)
+        .unwrap_or_else(|error| exit_error(error));
+    let path = path.to_string_lossy().to_string();
+    let was_default = config
+        .default_key_path
+        .as_ref()
+        .is_some_and(|default_key_path| shellexpand::tilde(default_key_path) == path);
+    if was_default {
+        config
+            .clone()
+            .with_default_key_path(None)
+            .save()
+            .unwrap_or_else(|error| exit_failed("write", DEFAULT_CONFIG_PATH, error));
+    }
+    eprintln!(
+        "{}{}",
+        style("removed key from keyring: ").color256(44),
+        style(path).color256(45)
+    );
+}
+fn key_default_command(matches: &ArgMatches, config: &Config) {
+    let name = match matches.value_of("name") {
+        Some(name) => name,
+        None => {
+            match config.default_key_path {
+                Some(ref path) => println!("{}", path),
+                None => eprintln!("{}", style("no default key").color256(202)),
+            }
+            return;
+        }
+    };
+    let keyring = Keyring::user();
+    keyring.get(name).unwrap_or_else(|error| exit_error(error));
+    let path = keyring
+        .key_path(name)
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    let filename = config
+        .clone()
+        .with_default_key_path(Some(path.clone()))
+        .save()
+        .unwrap_or_else(|error| exit_failed("write", DEFAULT_CONFIG_PATH, error));
+    eprintln!(
+        "{}{}{}{}",
+        style("default key: ").color256(44),
+        style(path).color256(45),
+        style(" in ").color256(44),
+        style(filename).color256(45)
+    );
+}
+fn key_info_command(matches: &ArgMatches) {
+    let filename = matches.value_of("key_filename").unwrap();
+    let protected = fs::read_to_string(filename)
+        .ok()
+        .and_then(|yaml| Key::from_yaml(yaml).ok())
+        .map(|key| key.is_protected())
+        .unwrap_or(false);
+    let key = import_key(filename);
+    let fields = [
+        ("file", String::from(filename)),
+        ("algo", key.algo.clone()),
+        (
+            "fingerprint",
+            key.fingerprint().unwrap_or_else(|error| exit_error(error)),
+        ),
+        ("kdf", describe_kdf(&key)),
+        (
+            "created",
+            key.created
+                .clone()
+                .unwrap_or_else(|| String::from("unknown")),
+        ),
+        (
+            "protected",
+            String::from(if protected { "yes" } else { "no" }),
+        ),
+    ];
+    for (name, value) in fields {
+        println!(
+            "{}{}",
+            style(format!("{}: ", name)).color256(44),
+            style(value).color256(45)
+        );
+    }
+}
+fn key_split_command(matches: &ArgMatches) {
+    let filename = matches.value_of("key_filename").unwrap();
+    let count = |name: &str| -> u8 {
+        matches.value_of(name).unwrap().parse().unwrap_or_else(|_| {
+            exit_error(Error::shares(format!(
+                "--{} must be between 2 and 255",
+                name
+            )))
+        })
+    };
+    let (shares, threshold) = (count("shares"), count("threshold"));
+    let prefix = matches.value_of("prefix").unwrap_or(filename);
+    let key = import_key(filename);
+    let shares = key
+        .split(shares, threshold)
+        .unwrap_or_else(|error| exit_error(error));
+    for share in &shares {
+        let share_filename = format!("{}.share{}", prefix, share.index);
+        let path = share
+            .export(&share_filename, matches.is_present("words"))
+            .unwrap_or_else(|error| {
+                exit_error(Error::shares(format!("{}: {}", share_filename, error)))
+            });
+        eprintln!(
+            "{}{}",
+            style("wrote share: ").color256(44),
+            style(path).color256(45)
+        );
+    }
+    eprintln!(
+        "{}",
+        style(format!(
+            "any {} of the {} shares recreate the key",
+            threshold,
+            shares.len()
+        ))
+        .color256(44)
+    );
+    print_fingerprint(&key);
+}
+fn key_combine_command(matches: &ArgMatches) {
+    let output = matches.value_of("output").unwrap();
+    if Path::new(output).exists() {
+        exit_error(Error::shares(format!("{} already exists", output)));
+    }
+    let shares = matches
+        .values_of("shares")
+        .unwrap()
+        .map(|filename| {
+            fs::read_to_string(filename)
+                .map_err(Error::from)
+                .and_then(|text| Share::from_text(&Zeroizing::new(text)))
+                .unwrap_or_else(|error| {
+                    exit_error(Error::shares(format!("bad share {}: {}", filename, error)))
+                })
+        })
+        .collect::<Vec<Share>>();
+    let key = Key::combine(&shares).unwrap_or_else(|error| exit_error(error));
+    let key_path = key
+        .export(output)
+        .unwrap_or_else(|error| exit_failed("write", output, error));
+    eprintln!(
+        "{}{}",
+        style("recreated key: ").color256(44),
+        style(key_path).color256(45)
+    );
+    print_fingerprint(&key);
+}
+fn rewrap_command(matches: &ArgMatches, config: &Config) {
+    let filename = matches.value_of("key_filename").unwrap();
+    let key = import_key(filename);
+    let key = key
+        .protect(confirm_passphrase().as_bytes(), config)
+        .unwrap_or_else(|error| exit_error(error));
+    let key_path = key
+        .export(filename)
+        .unwrap_or_else(|error| exit_failed("write", filename, error));
+    eprintln!(
+        "{}{}",
+        style("changed passphrase of key: ").color256(44),
+        style(key_path).color256(45)
+    );
+}
+fn rotate_command(matches: &ArgMatches) {
+    let old_key = import_key(matches.value_of("old_key").unwrap());
+    let new_key = import_key(matches.value_of("new_key").unwrap());
+
+    let mut paths = Vec::new();
+    for pattern in matches.values_of("targets").unwrap() {
+        match glob(pattern) {
+            Ok(entries) => {
+                paths.extend(entries.filter_map(Result::ok).filter(|path| path.is_file()))
+            }
+            Err(error) => {
+                eprintln!(
+                    "{}{}: {}",
+                    style("invalid glob pattern ").color256(202),
+                    style(pattern).color256(208),
+                    error
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    let bar = progress::default_bar(paths.len() as u64);
+    bar.set_prefix("rotating");
+    let (mut rotated, mut skipped, mut failed) = (0, 0, 0);
+    for path in &paths {
+        let filename = path.to_string_lossy().to_string();
+        bar.set_message(filename.clone());
+        match old_key.rotate_file(&new_key, &filename) {
+            Ok(()) => {
+                rotated += 1;
+                bar.println(format!(
+                    "{}{}",
+                    style("rotated: ").color256(49),
+                    style(&filename).color256(45)
+                ));
+            }
+            Err(Error::WrongKey) => {
+                skipped += 1;
+                bar.println(format!(
+                    "{}{}",
+                    style("skipping file not owned by the old key: ").color256(203),
+                    style(&filename).color256(208)
+                ));
+            }
+            Err(error) => {
+                failed += 1;
+                bar.println(format!(
+                    "{}{}: {}",
+                    style("failed to rotate ").color256(202),
+                    style(&filename).color256(208),
+                    error
+                ));
+            }
+        }
+        bar.inc(1);
+    }
+    bar.finish_with_message(format!(
+        "{} rotated, {} skipped, {} failed",
+        rotated, skipped, failed
+    ));
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+/// The key derivation of `--openssl`: PBKDF2 like `openssl enc -pbkdf2`,
+/// or the legacy `EVP_BytesToKey` when `--md` is given
+fn openssl_kdf(matches: &ArgMatches) -> OpensslKdf {
+    match matches.value_of("md") {
+        Some("md5") => OpensslKdf::BytesToKeyMd5,
+        Some(_) => OpensslKdf::BytesToKeySha256,
+        None => {
+            OpensslKdf::Pbkdf2(value_t!(matches, "iter", u32).unwrap_or(openssl::PBKDF2_ITERATIONS))
+        }
+    }
+}
+/// Checks the value of `--iter`, a positive number of PBKDF2 iterations
+fn is_iterations(iter: String) -> Result<(), String> {
+    match iter.parse::<u32>() {
+        Ok(iterations) if iterations > 0 => Ok(()),
+        _ => Err(String::from("must be a positive number")),
+    }
+}
+/// The password of `--openssl`, which takes no key files
+fn openssl_password(matches: &ArgMatches) -> Zeroizing<String> {
+    let password = get_password_from_matches(matches);
+    if password.is_empty() {
+        eprintln!(
+            "{}{}{}",
+            style("--openssl").color256(49),
+            style(" requires ").color256(195),
+            style("--ask-password or a --password option").color256(49),
+        );
+        std::process::exit(1);
+    }
+    password
+}
+fn openssl_encrypt_command(matches: &ArgMatches) {
+    let password = openssl_password(matches);
+    let in_place = matches.is_present("in_place");
+    let cyphertext_filename = match in_place {
+        true => matches.value_of("plaintext_filename").unwrap(),
+        false => matches.value_of("cyphertext_filename").unwrap(),
+    };
+    let shredded = matches
+        .value_of("plaintext_filename")
+        .and_then(|filename| open_shredded(matches, filename));
+    let mut input: Box<dyn Read> = match matches.value_of("plaintext_filename") {
+        Some(filename) => open_input(filename),
+        None => Box::new(matches.value_of("string").unwrap_or("").as_bytes()),
+    };
+    write_output(cyphertext_filename, |output| {
+        let mut writer =
+            openssl::encrypting_writer(password.as_bytes(), openssl_kdf(matches), output)?;
+        io::copy(&mut input, &mut writer)?;
+        writer.finish().map(|_| ())
+    })
+    .unwrap_or_else(|error| exit_failed("encrypt", cyphertext_filename, error));
+    if cyphertext_filename != STDIO {
+        println!(
+            "{}{}",
+            style("wrote encrypted data in: ").color256(207),
+            style(cyphertext_filename).color256(205)
+        );
+    }
+    if let Some(file) = shredded {
+        shred_plaintext(
+            file,
+            matches.value_of("plaintext_filename").unwrap(),
+            in_place,
+        );
+    }
+}
+fn openssl_decrypt_command(matches: &ArgMatches) {
+    let password = openssl_password(matches);
     let cyphertext_filename = matches.value_of("cyphertext_filename").unwrap();
+    let input = open_input(cyphertext_filename);
+    let mut reader =
+        match openssl::decrypting_reader(password.as_bytes(), openssl_kdf(matches), input) {
+            Ok(reader) => reader,
+            Err(error) => exit_failed("decrypt", cyphertext_filename, error),
+        };
+    let in_place = matches.is_present("in_place");
+    let plaintext_filename = match in_place {
+        true => Some(cyphertext_filename),
+        false => matches.value_of("plaintext_filename"),
+    };
+    let result = match plaintext_filename {
+        Some(plaintext_filename) => write_output(plaintext_filename, |output| {
+            io::copy(&mut reader, output)?;
+            Ok(())
+        }),
+        None => {
+            let mut plaintext = Vec::new();
+            reader
+                .read_to_end(&mut plaintext)
+                .map(|_| println!("{}", b64encode(&plaintext)))
+                .map_err(Error::from)
+        }
+    };
+    if let Err(error) = result {
+        exit_failed("decrypt", cyphertext_filename, error);
+    }
+    if let Some(plaintext_filename) = plaintext_filename.filter(|&filename| filename != STDIO) {
+        println!(
+            "{}{}",
+            style("wrote plaintext data in: ").color256(49),
+            style(plaintext_filename).color256(45)
+        );
+    }
+}
+fn encrypt_tree_command(matches: &ArgMatches, config: &Config) {
+    let directory = matches.value_of("directory").unwrap();
+    let key = load_key_file(matches, config);
+    match key.encrypt_tree(Path::new(directory), matches.is_present("include_hidden")) {
+        Ok(summary) => {
+            print_tree_summary(&summary, "encrypted: ", "skipping file already encrypted: ");
+            print_fingerprint(&key);
+        }
+        Err(error) => exit_failed("encrypt", directory, error),
+    }
+}
+fn decrypt_tree_command(matches: &ArgMatches, config: &Config) {
+    let directory = matches.value_of("directory").unwrap();
+    let key = load_key_file(matches, config);
+    match key.decrypt_tree(Path::new(directory)) {
+        Ok(summary) => {
+            print_tree_summary(
+                &summary,
+                "decrypted: ",
+                "skipping file not encrypted by the key: ",
+            );
+            print_fingerprint(&key);
+        }
+        Err(error) => exit_failed("decrypt", directory, error),
+    }
+}
+fn print_tree_summary(summary: &TreeSummary, processed: &str, skipped: &str) {
+    for path in &summary.skipped {
+        eprintln!(
+            "{}{}",
+            style(skipped).color256(162),
+            style(path).color256(136)
+        );
+    }
+    for path in &summary.processed {
+        println!(
+            "{}{}",
+            style(processed).color256(207),
+            style(path).color256(205)
+        );
+    }
+}
+/// Exits after failing to act on a file, a directory or an archive
+fn exit_failed(action: &str, target: &str, error: Error) -> ! {
+    eprintln!(
+        "{}",
+        style(format!(
+            "failed to {} {}: {}",
+            action,
+            style(target).color256(49),
+            error
+        ))
+        .color256(202)
+    );
+    std::process::exit(1);
+}
+fn pack_command(matches: &ArgMatches, config: &Config) {
+    let archive_filename = matches.value_of("archive_filename").unwrap();
+    let paths: Vec<&str> = matches.values_of("paths").unwrap().collect();
+    let key = load_key_file(matches, config);
+    match key.pack_file(archive_filename, &paths) {
+        Ok(entries) => {
+            println!(
+                "{}{}",
+                style(format!("packed {} entries in: ", entries.len())).color256(207),
+                style(archive_filename).color256(205)
+            );
+            print_fingerprint(&key);
+        }
+        Err(error) => exit_failed("pack", archive_filename, error),
+    }
+}
+fn unpack_command(matches: &ArgMatches, config: &Config) {
+    let archive_filename = matches.value_of("archive_filename").unwrap();
+    let directory = matches.value_of("directory").unwrap();
+    let key = load_key_file(matches, config);
+    match key.unpack_file(archive_filename, Path::new(directory)) {
+        Ok(entries) => {
+            for entry in entries {
+                println!(
+                    "{}{}",
+                    style("extracted: ").color256(49),
+                    style(entry.path).color256(45)
+                );
+            }
+            print_fingerprint(&key);
+        }
+        Err(error) => exit_failed("unpack", archive_filename, error),
+    }
+}
+fn list_command(matches: &ArgMatches, config: &Config) {
+    let archive_filename = matches.value_of("archive_filename").unwrap();
+    let key = load_key_file(matches, config);
+    match key.list_archive(archive_filename) {
+        Ok(entries) => {
+            for entry in entries {
+                let (kind, suffix) = match entry.kind {
+                    EntryKind::Directory => ("d", "/"),
+                    EntryKind::File => ("-", ""),
+                };
+                println!(
+                    "{}{:04o} {:>12} {}{}",
+                    kind,
+                    entry.mode,
+                    entry.size,
+                    style(entry.path).color256(45),
+                    suffix
+                );
+            }
+        }
+        Err(error) => exit_failed("list", archive_filename, error),
+    }
+}
+fn encrypt_command(matches: &ArgMatches, config: &Config) {
+    check_in_place(matches, "plaintext_filename");
+    if matches.is_present("openssl") {
+        return openssl_encrypt_command(matches);
+    }
+    let key_filenames: Vec<&str> = matches
+        .values_of("key_filename")
+        .map(|filenames| filenames.collect())
+        .unwrap_or_default();
+    // several key files encrypt for each of them with a random data key
+    let recipients: Vec<Key> = if key_filenames.len() > 1 {
+        key_filenames
+            .iter()
+            .map(|filename| import_key(filename))
+            .collect()
+    } else {
+        vec![load_key(matches, config, Some(&generate_salt()))]
+    };
+    let in_place = matches.is_present("in_place");
     let plaintext_string = matches.value_of("string").unwrap_or("");
     let plaintext_filename = matches.value_of("plaintext_filename").unwrap_or("");
+    let cyphertext_filename = match in_place {
+        true => plaintext_filename,
+        false => matches.value_of("cyphertext_filename").unwrap(),
+    };
 
-    if key.owns_file(plaintext_filename) {
+    if plaintext_filename.len() > 0
+        && plaintext_filename != STDIO
+        && recipients
+            .iter()
+            .any(|key| key.owns_file(plaintext_filename).unwrap_or(false))
+    {
         eprintln!(
             "{}{}",
             style("skipping file already encrypted: ").color256(162),
@@ -101,73 +1013,214 @@ fn encrypt_command(matches: &ArgMatches, config: &Config) {
         std::process::exit(1);
     }
 
-    let plaintext = if plaintext_filename.len() > 0 {
-        read_bytes(plaintext_filename)
+    let mut input: Box<dyn Read> = if plaintext_filename.len() > 0 {
+        open_input(plaintext_filename)
     } else if plaintext_string.len() > 0 {
-        plaintext_string.as_bytes().to_vec()
+        Box::new(plaintext_string.as_bytes())
     } else {
-        panic!(
+        eprintln!(
             "{}{}{}{}{}",
-            style("either").color256(195),
+            style("either ").color256(195),
             style("--string").color256(49),
             style(" or ").color256(195),
             style("--input-filename").color256(49),
             style(" is required").color256(195),
         );
+        std::process::exit(1);
     };
+    let shredded = open_shredded(matches, plaintext_filename);
 
-    let cyphertext = key.encrypt(&plaintext).ok().expect("encryption failed");
-    let mut file = File::create(cyphertext_filename).expect("failed to create new file");
-    file.write(&cyphertext).unwrap();
-    println!(
-        "{}{}",
-        style("wrote encrypted data in: ").color256(207),
-        style(cyphertext_filename).color256(205)
-    );
+    let armored = matches.is_present("armor");
+    let result = write_output(cyphertext_filename, |output| {
+        if armored {
+            armor::ArmoredWriter::new(output)
+                .and_then(|output| encrypt_to(&recipients, &mut input, output))
+                .and_then(|output| output.finish().map(|_| ()))
+        } else {
+            encrypt_to(&recipients, &mut input, output).map(|_| ())
+        }
+    });
+    if let Err(error) = result {
+        exit_failed("encrypt", cyphertext_filename, error);
+    }
+    if cyphertext_filename != STDIO {
+        println!(
+            "{}{}",
+            style("wrote encrypted data in: ").color256(207),
+            style(cyphertext_filename).color256(205)
+        );
+    }
+    for key in &recipients {
+        print_fingerprint(key);
+    }
+    if let Some(file) = shredded {
+        shred_plaintext(file, plaintext_filename, in_place);
+    }
+}
+
+fn encrypt_to<W: Write>(recipients: &[Key], input: &mut dyn Read, output: W) -> Result<W, Error> {
+    let mut writer = if recipients.len() > 1 {
+        EncryptingWriter::for_recipients(recipients, output)?
+    } else {
+        EncryptingWriter::new(&recipients[0], output)?
+    };
+    io::copy(input, &mut writer)?;
+    writer.finish()
 }
 
 fn decrypt_command(matches: &ArgMatches, config: &Config) {
+    check_in_place(matches, "cyphertext_filename");
+    if matches.is_present("openssl") {
+        return openssl_decrypt_command(matches);
+    }
     let key_filename = matches.value_of("key_filename").unwrap_or("");
+    let cyphertext_filename = matches.value_of("cyphertext_filename").unwrap();
 
-    let key = load_key(matches, config);
+    let input = if cyphertext_filename == STDIO {
+        armor::open_reader(io::stdin())
+    } else {
+        armor::open_file(cyphertext_filename)
+    };
+    let mut input = input.unwrap_or_else(|error| exit_failed("open", cyphertext_filename, error));
+    // the header records the config and salt of password-derived keys
+    // so that they do not depend on the current config
+    let envelope = match Envelope::read_from(&mut input) {
+        Ok(envelope) => envelope,
+        Err(Error::Io(ref error)) if error.kind() == io::ErrorKind::UnexpectedEof => exit_failed(
+            "decrypt",
+            cyphertext_filename,
+            Error::decode("too short to be encrypted"),
+        ),
+        Err(error) => exit_failed("decrypt", cyphertext_filename, error),
+    };
+    let header = match envelope {
+        Envelope::Container(ref header) => Some(header),
+        _ => None,
+    };
+    let file_config = header.and_then(|header| header.config());
+    let salt = header
+        .map(|header| header.salt.as_slice())
+        .filter(|salt| !salt.is_empty());
+    let key_config = file_config
+        .map(|file_config| file_config.with_default_key_path(config.default_key_path.clone()));
+    let key_config = key_config.as_ref().unwrap_or(config);
+    let keyring_key = match names_key(matches) {
+        false => Keyring::user()
+            .owner_of_envelope(&envelope)
+            .unwrap_or_else(|error| exit_error(error)),
+        true => None,
+    };
+    let key = match keyring_key {
+        Some((name, key)) => {
+            eprintln!(
+                "{}{}",
+                style("using key from keyring: ").color256(44),
+                style(name).color256(45)
+            );
+            key
+        }
+        None => load_key(matches, key_config, salt),
+    };
 
-    let cyphertext_filename = matches.value_of("cyphertext_filename").unwrap();
-    let plaintext_filename = matches.value_of("plaintext_filename").unwrap_or("");
+    let in_place = matches.is_present("in_place");
+    let plaintext_filename = match in_place {
+        true => cyphertext_filename,
+        false => matches.value_of("plaintext_filename").unwrap_or(""),
+    };
 
-    if !key.owns_file(cyphertext_filename) {
+    if !key
+        .owns_envelope(&envelope)
+        .unwrap_or_else(|error| exit_error(error))
+    {
         eprintln!(
             "{}{}",
-            style("skipping file not owned by the given key: ").color256(203),
+            style("not encrypted by the given key: ").color256(203),
             style(cyphertext_filename).color256(208)
         );
-        return;
+        std::process::exit(1);
+    }
+    let allow_unauthenticated = matches.is_present("allow_unauthenticated_legacy");
+    // stdin cannot be read twice, its tag is checked once it is decrypted:
+    // write_output only keeps a file it wrote if it was authenticated
+    let verified = match envelope {
+        _ if cyphertext_filename != STDIO => key.verify_file(cyphertext_filename),
+        Envelope::Digest(ref digest)
+            if !key
+                .has_random_iv(digest)
+                .unwrap_or_else(|error| exit_error(error)) =>
+        {
+            Err(Error::Unauthenticated)
+        }
+        _ => Ok(true),
+    };
+    match verified {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!(
+                "{}{}",
+                style("refusing to decrypt tampered or corrupted file: ").color256(203),
+                style(cyphertext_filename).color256(208)
+            );
+            std::process::exit(1);
+        }
+        Err(Error::Unauthenticated) if allow_unauthenticated => eprintln!(
+            "{}{}",
+            style("warning: tampering cannot be detected in legacy file: ").color256(202),
+            style(cyphertext_filename).color256(208)
+        ),
+        Err(Error::Unauthenticated) => {
+            eprintln!(
+                "{}{}{}",
+                style("refusing to decrypt legacy file without authentication tag: ").color256(203),
+                style(cyphertext_filename).color256(208),
+                style(" (pass --allow-unauthenticated-legacy to decrypt it anyway)").color256(202)
+            );
+            std::process::exit(1);
+        }
+        Err(error) => exit_failed("read", cyphertext_filename, error),
     }
 
-    let cyphertext = read_bytes(cyphertext_filename);
+    let input = io::Cursor::new(envelope.to_bytes()).chain(input);
+    let reader = match allow_unauthenticated {
+        true => DecryptingReader::with_unauthenticated_legacy(&key, input),
+        false => DecryptingReader::new(&key, input),
+    };
+    let mut reader =
+        reader.unwrap_or_else(|error| exit_failed("decrypt", cyphertext_filename, error));
+
+    let result = if plaintext_filename.len() > 0 {
+        write_output(plaintext_filename, |writer| {
+            io::copy(&mut reader, writer)?;
+            Ok(())
+        })
+    } else {
+        let mut decrypted_data = Vec::new();
+        reader
+            .read_to_end(&mut decrypted_data)
+            .map(|_| println!("{}", b64encode(&decrypted_data)))
+            .map_err(Error::from)
+    };
 
-    match key.decrypt(&cyphertext).ok() {
-        Some(decrypted_data) => {
-            if plaintext_filename.len() > 0 {
-                let mut file = File::create(plaintext_filename).expect("failed to create new file");
-                file.write(&decrypted_data)
-                    .expect("failed to write to output filename");
+    match result {
+        Ok(()) => {
+            if plaintext_filename.len() > 0 && plaintext_filename != STDIO {
                 println!(
                     "{}{}",
                     style("wrote plaintext data in: ").color256(49),
                     style(plaintext_filename).color256(45)
                 );
-            } else {
-                println!("{}", b64encode(&decrypted_data));
             }
+            print_fingerprint(&key);
         }
-        None => {
+        Err(error) => {
             eprintln!(
                 "{}",
                 style(format!(
-                    "failed to decrypt {} {} {}",
+                    "failed to decrypt {} {} {}: {}",
                     style(cyphertext_filename).color256(49),
                     style("with key").color256(202),
                     style(key_filename).color256(45),
+                    error,
                 ))
                 .color256(202)
             );
@@ -177,6 +1230,7 @@ fn decrypt_command(matches: &ArgMatches, config: &Config) {
 }
 
 fn main() {
+    let ciphers: Vec<&str> = Cipher::all().iter().map(|cipher| cipher.name()).collect();
     let app = App::new("aes256")
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .version(core::VERSION)
@@ -219,8 +1273,227 @@ fn main() {
                     Arg::with_name("ask_password")
                         .long("ask-password")
                         .short("p")
-                        .required_unless_one(&["password", "ask_password"])
+                        .required_unless_one(
+                            &[&PASSWORD_SOURCES[..], &["mnemonic", "ask_mnemonic"]].concat(),
+                        )
                         .takes_value(false),
+                )
+                .args(&password_source_args())
+                .arg(
+                    Arg::with_name("cipher")
+                        .long("cipher")
+                        .short("c")
+                        .help("the cipher used with the key")
+                        .possible_values(&ciphers)
+                        .default_value(Cipher::Aes256CbcHmacSha256.name())
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("mnemonic")
+                        .long("mnemonic")
+                        .value_name("PHRASE")
+                        .help("derive the key from a BIP39 mnemonic, the password being its optional passphrase")
+                        .conflicts_with("ask_mnemonic")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ask_mnemonic")
+                        .long("ask-mnemonic")
+                        .help("ask for the BIP39 mnemonic of the key on the terminal")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("protect")
+                        .long("protect")
+                        .help("protect the key file with a passphrase")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rewrap")
+                .about("change the passphrase of a key file, or protect it with one")
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("key")
+                .about("manage the keyring and inspect key files")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("list the keys of the keyring"))
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("copy a key file into the keyring")
+                        .arg(Arg::with_name("key_filename").required(true))
+                        .arg(
+                            Arg::with_name("name")
+                                .help("the name of the key, the file name by default"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("delete a key from the keyring")
+                        .arg(Arg::with_name("name").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("default")
+                        .about("show the default key, or use a key of the keyring by default")
+                        .arg(Arg::with_name("name")),
+                )
+                .subcommand(
+                    SubCommand::with_name("info")
+                        .about("show the algo, fingerprint, key derivation and creation time of a key file")
+                        .arg(Arg::with_name("key_filename").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("split")
+                        .about("split a key file into shares, any threshold of which recreate it")
+                        .arg(Arg::with_name("key_filename").required(true))
+                        .arg(
+                            Arg::with_name("shares")
+                                .long("shares")
+                                .short("n")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("threshold")
+                                .long("threshold")
+                                .short("t")
+                                .required(true)
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("words")
+                                .long("words")
+                                .help("write the shares as BIP39 words for printing"),
+                        )
+                        .arg(
+                            Arg::with_name("prefix")
+                                .long("prefix")
+                                .takes_value(true)
+                                .help("write the shares to <prefix>.share<n>, the key file name by default"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("combine")
+                        .about("recreate a key file from its shares")
+                        .arg(Arg::with_name("shares").multiple(true).required(true))
+                        .arg(
+                            Arg::with_name("output")
+                                .long("output")
+                                .short("o")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate")
+                .about("re-encrypt the files owned by a key with a new key")
+                .arg(
+                    Arg::with_name("old_key")
+                        .long("old-key")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("new_key")
+                        .long("new-key")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("targets")
+                        .help("glob patterns of the files to rotate")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("encrypt-tree")
+                .about("encrypt every file of a directory in place, with a manifest of their metadata")
+                .arg(Arg::with_name("directory").required(true))
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .help("key file, the default key otherwise")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("include_hidden")
+                        .long("include-hidden")
+                        .short("D")
+                        .help("include hidden files and directories")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("pack files and directories into one encrypted archive")
+                .arg(
+                    Arg::with_name("archive_filename")
+                        .long("output-filename")
+                        .short("o")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("paths").multiple(true).required(true))
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .help("key file, the default key otherwise")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("extract an encrypted archive")
+                .arg(Arg::with_name("archive_filename").required(true))
+                .arg(
+                    Arg::with_name("directory")
+                        .long("directory")
+                        .short("C")
+                        .help("the directory to extract into, the current one by default")
+                        .default_value(".")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .help("key file, the default key otherwise")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("list the entries of an encrypted archive")
+                .arg(Arg::with_name("archive_filename").required(true))
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .help("key file, the default key otherwise")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("decrypt-tree")
+                .about("decrypt a directory encrypted by encrypt-tree, restoring permissions and modification times")
+                .arg(Arg::with_name("directory").required(true))
+                .arg(
+                    Arg::with_name("key_filename")
+                        .long("key-filename")
+                        .short("k")
+                        .help("key file, the default key otherwise")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
@@ -243,14 +1516,28 @@ fn main() {
                     Arg::with_name("cyphertext_filename")
                         .long("output-filename")
                         .short("o")
-                        .required(true)
+                        .required_unless("in_place")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("in_place")
+                        .long("in-place")
+                        .help("replace the input file with its cyphertext once it is complete")
+                        .requires("plaintext_filename")
+                        .conflicts_with_all(&["cyphertext_filename", "string"])
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("shred")
+                        .long("shred")
+                        .help("overwrite the input file with random bytes, then delete it")
+                        .requires("plaintext_filename")
+                        .takes_value(false),
+                )
                 .arg(
                     Arg::with_name("password")
                         .long("password")
                         .short("P")
-                        .required_unless_one(&["key_filename", "ask_password"])
                         .required(false)
                         .takes_value(true),
                 )
@@ -261,11 +1548,45 @@ fn main() {
                         .short("p")
                         .takes_value(false),
                 )
+                .args(&password_source_args())
+                .arg(
+                    Arg::with_name("openssl")
+                        .long("openssl")
+                        .help("read and write the files of openssl enc -aes-256-cbc with a password")
+                        .conflicts_with_all(&["key_filename", "armor"])
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("iter")
+                        .long("iter")
+                        .help("PBKDF2 iterations of --openssl, 10000 by default")
+                        .validator(is_iterations)
+                        .requires("openssl")
+                        .conflicts_with("md")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("md")
+                        .long("md")
+                        .help("derive the key of --openssl with the legacy EVP_BytesToKey and this digest")
+                        .requires("openssl")
+                        .possible_values(&["sha256", "md5"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("armor")
+                        .long("armor")
+                        .short("a")
+                        .help("write an ASCII-armored cyphertext that can be pasted as text")
+                        .takes_value(false),
+                )
                 .arg(
                     Arg::with_name("key_filename")
                         .long("key-filename")
                         .short("k")
-                        .required_unless_one(&["password", "ask_password"])
+                        .help("key file, repeat it to encrypt for several keys")
+                        .multiple(true)
+                        .number_of_values(1)
                         .takes_value(true),
                 ),
         )
@@ -293,10 +1614,49 @@ fn main() {
                         .required(false)
                         .takes_value(false),
                 )
+                .args(&password_source_args())
                 .arg(
                     Arg::with_name("plaintext_filename")
                         .long("output-filename")
                         .short("o")
+                        .help("the plaintext file, written once authenticated, or - for stdout, which gets the plaintext of stdin before its tag is checked at the end")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("in_place")
+                        .long("in-place")
+                        .help("replace the input file with its plaintext once it is complete")
+                        .conflicts_with("plaintext_filename")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("allow_unauthenticated_legacy")
+                        .long("allow-unauthenticated-legacy")
+                        .help("decrypt legacy files without authentication tag, which cannot be checked for tampering")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("openssl")
+                        .long("openssl")
+                        .help("decrypt a file of openssl enc -aes-256-cbc with a password")
+                        .conflicts_with_all(&["key_filename"])
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("iter")
+                        .long("iter")
+                        .help("PBKDF2 iterations of --openssl, 10000 by default")
+                        .validator(is_iterations)
+                        .requires("openssl")
+                        .conflicts_with("md")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("md")
+                        .long("md")
+                        .help("derive the key of --openssl with the legacy EVP_BytesToKey and this digest")
+                        .requires("openssl")
+                        .possible_values(&["sha256", "md5"])
                         .takes_value(true),
                 )
                 .arg(
@@ -311,12 +1671,44 @@ fn main() {
     let matches = app.get_matches();
     //let dry_run = matches.is_present("dry_run");
 
-    let config = Config::default().expect("cannot read default config: ~/.toolz.yaml");
+    let config =
+        Config::default().unwrap_or_else(|error| exit_failed("read", DEFAULT_CONFIG_PATH, error));
 
     match matches.subcommand() {
         ("generate", Some(matches)) => {
             generate_command(matches, &config);
         }
+        ("rewrap", Some(matches)) => {
+            rewrap_command(matches, &config);
+        }
+        ("rotate", Some(matches)) => {
+            rotate_command(matches);
+        }
+        ("encrypt-tree", Some(matches)) => {
+            encrypt_tree_command(matches, &config);
+        }
+        ("decrypt-tree", Some(matches)) => {
+            decrypt_tree_command(matches, &config);
+        }
+        ("pack", Some(matches)) => {
+            pack_command(matches, &config);
+        }
+        ("unpack", Some(matches)) => {
+            unpack_command(matches, &config);
+        }
+        ("list", Some(matches)) => {
+            list_command(matches, &config);
+        }
+        ("key", Some(matches)) => match matches.subcommand() {
+            ("info", Some(matches)) => key_info_command(matches),
+            ("list", Some(_)) => key_list_command(&config),
+            ("add", Some(matches)) => key_add_command(matches),
+            ("remove", Some(matches)) => key_remove_command(matches, &config),
+            ("default", Some(matches)) => key_default_command(matches, &config),
+            ("split", Some(matches)) => key_split_command(matches),
+            ("combine", Some(matches)) => key_combine_command(matches),
+            (cmd, _) => eprintln!("command not implemented: key {}", cmd),
+        },
         ("encrypt", Some(matches)) => {
             encrypt_command(matches, &config);
         }
@@ -331,3 +1723,30 @@ fn main() {
         }
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::read_fd;
+    use k9::assert_equal;
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_read_fd_leaves_the_descriptor_open() {
+        let mut child = Command::new("printf")
+            .arg("I <3 Nickelback\\n")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut pipe = child.stdout.take().unwrap();
+        let password = read_fd(&pipe.as_raw_fd().to_string()).unwrap();
+        assert_equal!(password.as_str(), "I <3 Nickelback\n");
+
+        // reading a closed descriptor would fail
+        let mut rest = String::new();
+        pipe.read_to_string(&mut rest).unwrap();
+        assert_equal!(rest, "");
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+/*!
+AEAD ciphers in chunks
+
+An AEAD tag can only be checked once the whole message went through it, so
+the data is sealed in chunks of `CHUNK_SIZE` bytes, each followed by its own
+16 bytes tag. Only the last chunk may be shorter, and it is always present,
+even when empty.
+
+The nonce of each chunk is the random nonce from the header with its last
+five bytes XORed with the chunk counter and a flag set on the last chunk,
+so chunks cannot be reordered, dropped or appended. Every chunk authenticates
+the container header and the associated data, if any.
+
+AES-256-GCM comes from `rust-crypto`, ChaCha20-Poly1305 and its extended
+nonce variant XChaCha20-Poly1305 from the `chacha20poly1305` crate.
+*/
+use super::format::Cipher;
+use super::Error;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use zeroize::Zeroizing;
+
+///The size of a plaintext chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+///The size of the tag following each chunk
+pub const TAG_SIZE: usize = 16;
+///The size of the AES-256-GCM and ChaCha20-Poly1305 nonces
+pub const NONCE_SIZE: usize = 12;
+///The size of the XChaCha20-Poly1305 nonce
+pub const XNONCE_SIZE: usize = 24;
+
+/// The nonce of the chunk number `counter`
+fn chunk_nonce(nonce: &[u8], counter: u32, last: bool) -> Vec<u8> {
+    let mut chunk_nonce = nonce.to_vec();
+    let flag = chunk_nonce.len() - 1;
+    for (byte, counter) in chunk_nonce[flag - 4..flag]
+        .iter_mut()
+        .zip(counter.to_be_bytes())
+    {
+        *byte ^= counter;
+    }
+    chunk_nonce[flag] ^= last as u8;
+    chunk_nonce
+}
+
+/// Encrypts `plaintext`, returning it followed by its tag
+pub(crate) fn seal_chunk(
+    cipher: Cipher,
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    match cipher {
+        Cipher::Aes256Gcm => {
+            let mut sealed = vec![0; plaintext.len() + TAG_SIZE];
+            let (cyphertext, tag) = sealed.split_at_mut(plaintext.len());
+            AesGcm::new(KeySize::KeySize256, key, nonce, aad).encrypt(plaintext, cyphertext, tag);
+            Ok(sealed)
+        }
+        Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key)
+            .map_err(Error::decode)?
+            .encrypt(nonce.into(), payload)
+            .map_err(|_| Error::unsupported("chunk too long")),
+        Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new_from_slice(key)
+            .map_err(Error::decode)?
+            .encrypt(nonce.into(), payload)
+            .map_err(|_| Error::unsupported("chunk too long")),
+        Cipher::Aes256CbcHmacSha256 => Err(Error::unsupported("not an AEAD cipher")),
+    }
+}
+
+/// Decrypts a chunk followed by its tag, failing with `Error::Integrity`
+/// if the tag does not match
+pub(crate) fn open_chunk(
+    cipher: Cipher,
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if sealed.len() < TAG_SIZE {
+        return Err(Error::Integrity);
+    }
+    let payload = Payload { msg: sealed, aad };
+    match cipher {
+        Cipher::Aes256Gcm => {
+            let (cyphertext, tag) = sealed.split_at(sealed.len() - TAG_SIZE);
+            let mut plaintext = vec![0; cyphertext.len()];
+            let mut gcm = AesGcm::new(KeySize::KeySize256, key, nonce, aad);
+            if !gcm.decrypt(cyphertext, &mut plaintext, tag) {
+                return Err(Error::Integrity);
+            }
+            Ok(plaintext)
+        }
+        Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key)
+            .map_err(Error::decode)?
+            .decrypt(nonce.into(), payload)
+            .map_err(|_| Error::Integrity),
+        Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new_from_slice(key)
+            .map_err(Error::decode)?
+            .decrypt(nonce.into(), payload)
+            .map_err(|_| Error::Integrity),
+        Cipher::Aes256CbcHmacSha256 => Err(Error::unsupported("not an AEAD cipher")),
+    }
+}
+
+/// The cipher, chunk counter and associated data shared by `Sealer` and `Opener`
+struct Chunks {
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+    nonce: Vec<u8>,
+    aad: Vec<u8>,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl Chunks {
+    /// Checks the key and nonce sizes, the ciphers panic on anything else
+    fn new(cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Chunks, Error> {
+        if !cipher.is_aead() {
+            return Err(Error::unsupported("not an AEAD cipher"));
+        }
+        if key.len() != 32 {
+            return Err(Error::decode("invalid cipher key size"));
+        }
+        if nonce.len() != cipher.iv_size() {
+            return Err(Error::decode("invalid nonce size"));
+        }
+        Ok(Chunks {
+            cipher,
+            key: Zeroizing::new(key.to_vec()),
+            nonce: nonce.to_vec(),
+            aad: aad.to_vec(),
+            counter: 0,
+            buffer: Vec::new(),
+        })
+    }
+    /// The nonce of the next chunk
+    fn next(&mut self, last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = chunk_nonce(&self.nonce, self.counter, last);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| Error::unsupported("message too long"))?;
+        Ok(nonce)
+    }
+}
+
+/// Encrypts data written through `update` into chunks
+pub struct Sealer {
+    chunks: Chunks,
+}
+
+impl Sealer {
+    pub fn new(cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Sealer, Error> {
+        Ok(Sealer {
+            chunks: Chunks::new(cipher, key, nonce, aad)?,
+        })
+    }
+    fn seal(&mut self, size: usize, last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = self.chunks.next(last)?;
+        let plaintext: Vec<u8> = self.chunks.buffer.drain(..size).collect();
+        let chunks = &self.chunks;
+        seal_chunk(chunks.cipher, &chunks.key, &nonce, &chunks.aad, &plaintext)
+    }
+    /// Buffers `data`, returning the chunks that are complete
+    ///
+    /// A full chunk is held back until more data comes, as it might be the last.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.chunks.buffer.extend_from_slice(data);
+        let mut sealed = Vec::new();
+        while self.chunks.buffer.len() > CHUNK_SIZE {
+            sealed.extend(self.seal(CHUNK_SIZE, false)?);
+        }
+        Ok(sealed)
+    }
+    /// Seals the last chunk
+    pub fn finish(mut self) -> Result<Vec<u8>, Error> {
+        let size = self.chunks.buffer.len();
+        self.seal(size, true)
+    }
+}
+
+/// Decrypts and authenticates chunks read through `update`
+pub struct Opener {
+    chunks: Chunks,
+}
+
+impl Opener {
+    pub fn new(cipher: Cipher, key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Opener, Error> {
+        Ok(Opener {
+            chunks: Chunks::new(cipher, key, nonce, aad)?,
+        })
+    }
+    fn open(&mut self, size: usize, last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = self.chunks.next(last)?;
+        let sealed: Vec<u8> = self.chunks.buffer.drain(..size).collect();
+        let chunks = &self.chunks;
+        open_chunk(chunks.cipher, &chunks.key, &nonce, &chunks.aad, &sealed)
+    }
+    /// Buffers `data`, returning the plaintext of the chunks that are complete
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.chunks.buffer.extend_from_slice(data);
+        let mut plaintext = Vec::new();
+        while self.chunks.buffer.len() > CHUNK_SIZE + TAG_SIZE {
+            plaintext.extend(self.open(CHUNK_SIZE + TAG_SIZE, false)?);
+        }
+        Ok(plaintext)
+    }
+    /// Opens the last chunk, failing with `Error::Integrity` if the
+    /// message was truncated
+    pub fn finish(mut self) -> Result<Vec<u8>, Error> {
+        let size = self.chunks.buffer.len();
+        self.open(size, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::aead::{CHUNK_SIZE, TAG_SIZE};
+    use crate::aes256cbc::format::Cipher;
+    use crate::aes256cbc::{DecryptingReader, EncryptingWriter, Error, Key};
+    use k9::assert_equal;
+    use std::io::{Read, Write};
+
+    const AEAD_CIPHERS: [Cipher; 3] = [
+        Cipher::Aes256Gcm,
+        Cipher::ChaCha20Poly1305,
+        Cipher::XChaCha20Poly1305,
+    ];
+
+    #[test]
+    fn test_aead_roundtrip_across_chunks() {
+        let plaintext: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        for cipher in AEAD_CIPHERS {
+            let key = Key::generate().with_cipher(cipher);
+
+            let mut writer = EncryptingWriter::new(&key, Vec::new()).unwrap();
+            for chunk in plaintext.chunks(7777) {
+                writer.write_all(chunk).unwrap();
+            }
+            let cyphertext = writer.finish().unwrap();
+            assert_equal!(key.decrypt(&cyphertext).unwrap(), plaintext);
+            assert!(key.verify(cyphertext.as_slice()).unwrap());
+
+            let mut reader = DecryptingReader::new(&key, cyphertext.as_slice()).unwrap();
+            let mut decrypted = Vec::new();
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_equal!(decrypted, plaintext);
+
+            let empty = key.encrypt(b"").unwrap();
+            assert_equal!(key.decrypt(&empty).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn test_aead_authenticates_chunks_and_associated_data() {
+        for cipher in AEAD_CIPHERS {
+            let key = Key::generate().with_cipher(cipher);
+            let cyphertext = key
+                .encrypt_with_aad(&[7; CHUNK_SIZE + 1], b"context")
+                .unwrap();
+            assert_equal!(
+                key.decrypt_with_aad(&cyphertext, b"context").unwrap(),
+                vec![7; CHUNK_SIZE + 1]
+            );
+
+            let other_aad = key.decrypt_with_aad(&cyphertext, b"other context");
+            assert!(matches!(other_aad, Err(Error::Integrity)));
+
+            let truncated = &cyphertext[..cyphertext.len() - 1 - TAG_SIZE];
+            assert!(matches!(
+                key.decrypt_with_aad(truncated, b"context"),
+                Err(Error::Integrity)
+            ));
+
+            let mut tampered = cyphertext.clone();
+            tampered[100] ^= 1;
+            assert!(matches!(
+                key.decrypt_with_aad(&tampered, b"context"),
+                Err(Error::Integrity)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_cipher_is_bound_to_the_key() {
+        let key = Key::generate().with_cipher(Cipher::ChaCha20Poly1305);
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+
+        let other_cipher = key.with_cipher(Cipher::XChaCha20Poly1305);
+        assert!(matches!(
+            other_cipher.decrypt(&cyphertext),
+            Err(Error::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn test_associated_data_requires_an_aead_cipher() {
+        let result = Key::generate().encrypt_with_aad(b"This is a secret", b"context");
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+}
@@ -0,0 +1,542 @@
+/*!
+Encrypted archives
+
+`Key::pack_file` bundles files and directories into a single file encrypted
+with the streaming API, which hides their names, count and sizes: only the
+total size shows. `Key::list_archive` lists its entries and
+`Key::unpack_file` extracts them.
+
+Inside the encryption, an archive is the `TOOLZARC` magic and a version
+byte, followed by one record per entry, each directory before its content,
+and a last record of kind 0:
+
+```text
+kind          1 byte: 1 for a file, 2 for a directory
+path length   2 bytes, big-endian
+path          `/`-separated UTF-8 path, relative to the extraction directory
+mode          4 bytes, the permission bits
+modified      8 bytes of seconds and 4 bytes of nanoseconds since the Unix epoch
+size          8 bytes, 0 for directories
+data          `size` bytes
+```
+
+Symbolic links are neither followed nor stored. Extraction refuses absolute
+paths, `..` components and paths through symbolic links, never overwrites
+existing files, drops the setuid, setgid and sticky bits, and only starts
+once the whole archive is authenticated, through the same open file so that
+replacing it in between does not matter.
+
+# Example
+
+```
+use std::fs;
+use toolz::aes256cbc::Key;
+
+let root = std::env::temp_dir().join(format!("archive-doc-{}", std::process::id()));
+fs::create_dir_all(root.join("notes")).unwrap();
+fs::write(root.join("notes/todo.txt"), b"Buy milk").unwrap();
+let archive = root.join("notes.aes");
+
+let key = Key::generate();
+let notes = root.join("notes");
+key.pack_file(archive.to_str().unwrap(), &[notes.to_str().unwrap()]).unwrap();
+let entries = key.list_archive(archive.to_str().unwrap()).unwrap();
+assert!(entries[1].path.ends_with("notes/todo.txt"));
+
+key.unpack_file(archive.to_str().unwrap(), &root.join("restored")).unwrap();
+let restored = root.join("restored").join(&entries[1].path);
+assert_eq!(fs::read(restored).unwrap(), b"Buy milk".to_vec());
+fs::remove_dir_all(root).unwrap();
+```
+*/
+use super::tree::{mode_of, modified_of, path_str, restore_metadata, safe_join};
+use super::{armor, replace_file, DecryptingReader, EncryptingWriter, Error, Key};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+use std::time::Duration;
+
+///The magic bytes starting the plaintext of an archive
+const ARCHIVE_MAGIC: &[u8] = b"TOOLZARC";
+///The version of the layout of an archive
+const ARCHIVE_VERSION: u8 = 1;
+///The kind of the record ending an archive
+const END_RECORD: u8 = 0;
+const FILE_RECORD: u8 = 1;
+const DIRECTORY_RECORD: u8 = 2;
+///The permission bits restored on extraction, without the setuid, setgid
+///and sticky bits, as `tar` does for users other than root
+const EXTRACTED_MODE_MASK: u32 = 0o777;
+
+/// Whether an archive entry is a file or a directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A file or directory of an archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub kind: EntryKind,
+    /// The `/`-separated path relative to the extraction directory
+    pub path: String,
+    /// The permission bits
+    pub mode: u32,
+    /// The modification time, since the Unix epoch
+    pub modified: Duration,
+    /// The size of the data of a file, 0 for directories
+    pub size: u64,
+}
+
+/// Writes files and directories as archive records into `inner`
+///
+/// `finish` must be called to write the last record.
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Wraps `inner`, writing the magic bytes right away
+    pub fn new(mut inner: W) -> Result<ArchiveWriter<W>, Error> {
+        inner.write_all(ARCHIVE_MAGIC)?;
+        inner.write_all(&[ARCHIVE_VERSION])?;
+        Ok(ArchiveWriter {
+            inner,
+            entries: Vec::new(),
+        })
+    }
+    /// Adds a file, or a directory and its content, named after its path
+    /// without its root, `.` and `..` components like `tar` does
+    ///
+    /// The content of a directory named `.` is added at the top.
+    pub fn add(&mut self, path: &Path) -> Result<(), Error> {
+        let name = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(path_str(Path::new(name))),
+                _ => None,
+            })
+            .collect::<Result<Vec<&str>, Error>>()?
+            .join("/");
+        if name.is_empty() && path.is_dir() {
+            return self.add_children(path, "");
+        }
+        self.add_as(path, &name)
+    }
+    /// Adds a file, or a directory and its content, as `name`
+    pub fn add_as(&mut self, path: &Path, name: &str) -> Result<(), Error> {
+        safe_join(Path::new(""), name)?;
+        let metadata = fs::symlink_metadata(path)?;
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else if metadata.is_file() {
+            EntryKind::File
+        } else {
+            return Ok(());
+        };
+        let entry = ArchiveEntry {
+            kind,
+            path: String::from(name),
+            mode: mode_of(&metadata),
+            modified: modified_of(&metadata)?,
+            size: if kind == EntryKind::File {
+                metadata.len()
+            } else {
+                0
+            },
+        };
+        self.write_record(&entry)?;
+        self.entries.push(entry.clone());
+        match kind {
+            EntryKind::File => {
+                let mut data = File::open(path)?.take(entry.size);
+                if io::copy(&mut data, &mut self.inner)? != entry.size {
+                    return Err(Error::decode(format!(
+                        "{} changed while packing",
+                        path.display()
+                    )));
+                }
+                Ok(())
+            }
+            EntryKind::Directory => self.add_children(path, name),
+        }
+    }
+    fn add_children(&mut self, path: &Path, prefix: &str) -> Result<(), Error> {
+        let mut children = fs::read_dir(path)?.collect::<Result<Vec<fs::DirEntry>, io::Error>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let file_name = child.file_name();
+            let name = path_str(Path::new(&file_name))?;
+            let name = match prefix {
+                "" => String::from(name),
+                prefix => format!("{}/{}", prefix, name),
+            };
+            self.add_as(&child.path(), &name)?;
+        }
+        Ok(())
+    }
+    fn write_record(&mut self, entry: &ArchiveEntry) -> Result<(), Error> {
+        if entry.path.len() > u16::MAX as usize {
+            return Err(Error::decode(format!("path too long: {}", entry.path)));
+        }
+        let kind = match entry.kind {
+            EntryKind::File => FILE_RECORD,
+            EntryKind::Directory => DIRECTORY_RECORD,
+        };
+        self.inner.write_all(&[kind])?;
+        self.inner
+            .write_all(&(entry.path.len() as u16).to_be_bytes())?;
+        self.inner.write_all(entry.path.as_bytes())?;
+        self.inner.write_all(&entry.mode.to_be_bytes())?;
+        self.inner
+            .write_all(&entry.modified.as_secs().to_be_bytes())?;
+        self.inner
+            .write_all(&entry.modified.subsec_nanos().to_be_bytes())?;
+        self.inner.write_all(&entry.size.to_be_bytes())?;
+        Ok(())
+    }
+    /// Writes the last record, returning the inner writer and the entries
+    /// written
+    pub fn finish(mut self) -> Result<(W, Vec<ArchiveEntry>), Error> {
+        self.inner.write_all(&[END_RECORD])?;
+        Ok((self.inner, self.entries))
+    }
+}
+
+/// Reads the entries of an archive from `inner`
+pub struct ArchiveReader<R: Read> {
+    inner: R,
+    remaining: u64,
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Reads the magic bytes, failing with `Error::Decode` if `inner` is
+    /// not an archive
+    pub fn new(mut inner: R) -> Result<ArchiveReader<R>, Error> {
+        let header: [u8; 9] = read_array(&mut inner)?;
+        if &header[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+            return Err(Error::decode("not an archive"));
+        }
+        if header[ARCHIVE_MAGIC.len()] != ARCHIVE_VERSION {
+            return Err(Error::unsupported(format!(
+                "archive version {}",
+                header[ARCHIVE_MAGIC.len()]
+            )));
+        }
+        Ok(ArchiveReader {
+            inner,
+            remaining: 0,
+        })
+    }
+    /// The next entry, `None` after the last one
+    ///
+    /// The data of the previous entry is skipped if it was not read.
+    pub fn next_entry(&mut self) -> Result<Option<ArchiveEntry>, Error> {
+        self.read_data(&mut io::sink())?;
+        let [kind] = read_array(&mut self.inner)?;
+        let kind = match kind {
+            END_RECORD => return Ok(None),
+            FILE_RECORD => EntryKind::File,
+            DIRECTORY_RECORD => EntryKind::Directory,
+            kind => return Err(Error::decode(format!("unknown archive record: {}", kind))),
+        };
+        let mut path = vec![0u8; u16::from_be_bytes(read_array(&mut self.inner)?) as usize];
+        self.inner.read_exact(&mut path)?;
+        let path = String::from_utf8(path).map_err(Error::decode)?;
+        let mode = u32::from_be_bytes(read_array(&mut self.inner)?);
+        let seconds = u64::from_be_bytes(read_array(&mut self.inner)?);
+        let nanos = u32::from_be_bytes(read_array(&mut self.inner)?);
+        let size = u64::from_be_bytes(read_array(&mut self.inner)?);
+        if nanos >= 1_000_000_000 || (kind == EntryKind::Directory && size > 0) {
+            return Err(Error::decode(format!("invalid archive entry: {}", path)));
+        }
+        self.remaining = size;
+        Ok(Some(ArchiveEntry {
+            kind,
+            path,
+            mode,
+            modified: Duration::new(seconds, nanos),
+            size,
+        }))
+    }
+    /// Copies the data of the current entry into `output`
+    pub fn read_data(&mut self, output: &mut dyn Write) -> Result<u64, Error> {
+        let expected = self.remaining;
+        let copied = io::copy(&mut (&mut self.inner).take(expected), output)?;
+        if copied != expected {
+            return Err(Error::decode("truncated archive"));
+        }
+        self.remaining = 0;
+        Ok(copied)
+    }
+    /// Checks that nothing follows the last entry, reading `inner` to the
+    /// end so that a decrypting reader checks its authentication tag
+    pub fn finish(mut self) -> Result<R, Error> {
+        if io::copy(&mut self.inner, &mut io::sink())? > 0 {
+            return Err(Error::decode("data after the last archive entry"));
+        }
+        Ok(self.inner)
+    }
+}
+
+/// Fails if a directory on the way from `root` to `relative` is a symbolic
+/// link, which could lead extraction out of `root`
+fn check_no_symlinks(root: &Path, relative: &str) -> Result<(), Error> {
+    let mut path = root.to_path_buf();
+    for component in relative.split('/') {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(Error::decode(format!(
+                    "refusing to extract through a symbolic link: {}",
+                    path.display()
+                )))
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+impl Key {
+    /// Packs files and directories into an archive file encrypted with this
+    /// key, replacing `filename` only once it is complete
+    pub fn pack_file(&self, filename: &str, paths: &[&str]) -> Result<Vec<ArchiveEntry>, Error> {
+        let output = Path::new(filename);
+        let output_directory = match output.parent() {
+            Some(parent) if parent != Path::new("") => parent.canonicalize()?,
+            _ => std::env::current_dir()?,
+        };
+        for path in paths {
+            let path = Path::new(path);
+            if path.is_dir() && output_directory.starts_with(path.canonicalize()?) {
+                return Err(Error::decode(format!(
+                    "cannot write {} inside the packed directory {}",
+                    filename,
+                    path.display()
+                )));
+            }
+        }
+        let mut entries = Vec::new();
+        replace_file(filename, |file| {
+            let writer = EncryptingWriter::new(self, BufWriter::new(file))?;
+            let mut archive = ArchiveWriter::new(writer)?;
+            for path in paths {
+                archive.add(Path::new(path))?;
+            }
+            let (writer, added) = archive.finish()?;
+            writer.finish()?.flush()?;
+            entries = added;
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+    /// Lists the entries of an archive file
+    ///
+    /// Fails with `Error::WrongKey` if the archive is not owned by this key.
+    pub fn list_archive(&self, filename: &str) -> Result<Vec<ArchiveEntry>, Error> {
+        let mut archive = self.open_archive(&File::open(filename)?)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry()? {
+            entries.push(entry);
+        }
+        archive.finish()?;
+        Ok(entries)
+    }
+    /// Extracts an archive file under `destination`, restoring permissions
+    /// but the setuid, setgid and sticky bits, and modification times
+    ///
+    /// Fails with `Error::Integrity`, before writing anything, if the
+    /// archive was tampered with, and with `Error::Decode` on entries that
+    /// would be extracted out of `destination`.
+    pub fn unpack_file(
+        &self,
+        filename: &str,
+        destination: &Path,
+    ) -> Result<Vec<ArchiveEntry>, Error> {
+        // the file authenticated is the one extracted, even if the path is
+        // replaced in between, as both read the same open file
+        let file = File::open(filename)?;
+        self.open_archive(&file)?;
+        if !self.verify(rewind(&file)?)? {
+            return Err(Error::Integrity);
+        }
+        let mut archive = self.open_archive(&file)?;
+        fs::create_dir_all(destination)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry()? {
+            let path = safe_join(destination, &entry.path)?;
+            check_no_symlinks(destination, &entry.path)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match entry.kind {
+                EntryKind::Directory => match fs::create_dir(&path) {
+                    Err(error) if error.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => {
+                    }
+                    result => result?,
+                },
+                EntryKind::File => {
+                    let file = fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(&path)
+                        .map_err(|error| {
+                            io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+                        })?;
+                    let mut output = BufWriter::new(file);
+                    archive.read_data(&mut output)?;
+                    output.flush()?;
+                    restore_metadata(&path, entry.mode & EXTRACTED_MODE_MASK, entry.modified)?;
+                }
+            }
+            entries.push(entry);
+        }
+        archive.finish()?;
+        // extracting their content changed the modification time of the
+        // directories, the deepest ones are restored first
+        for entry in entries.iter().rev() {
+            if entry.kind == EntryKind::Directory {
+                restore_metadata(
+                    &safe_join(destination, &entry.path)?,
+                    entry.mode & EXTRACTED_MODE_MASK,
+                    entry.modified,
+                )?;
+            }
+        }
+        Ok(entries)
+    }
+    /// Reads the archive from the start of an open file
+    ///
+    /// Fails with `Error::WrongKey` if it is not owned by this key.
+    fn open_archive(
+        &self,
+        file: &File,
+    ) -> Result<ArchiveReader<DecryptingReader<Box<dyn Read>>>, Error> {
+        match DecryptingReader::new(self, rewind(file)?) {
+            Ok(reader) => ArchiveReader::new(reader),
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(Error::WrongKey)
+            }
+            Err(Error::Decode(_)) => Err(Error::WrongKey),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Reads an open file again from its start, decoding it if it is armored
+fn rewind(file: &File) -> Result<Box<dyn Read>, Error> {
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+    armor::open_reader(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::archive::{ArchiveWriter, EntryKind};
+    use crate::aes256cbc::{Error, Key};
+    use k9::assert_equal;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_unpack_restores_the_packed_tree() {
+        let root = std::env::temp_dir().join(format!("archive-{}", std::process::id()));
+        let project = root.join("project");
+        fs::create_dir_all(project.join("src/empty")).unwrap();
+        fs::write(project.join("src/main.rs"), b"fn main() {}").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(
+                project.join("src/main.rs"),
+                fs::Permissions::from_mode(0o4755),
+            )
+            .unwrap();
+        }
+        fs::write(project.join("Cargo.toml"), b"[package]").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(project.join("Cargo.toml"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::new(1_600_000_000, 42))
+            .unwrap();
+        let archive = root.join("project.aes");
+        let archive = archive.to_str().unwrap();
+
+        let key = Key::generate();
+        let packed = key
+            .pack_file(archive, &[project.to_str().unwrap()])
+            .unwrap();
+        let prefix = project.to_str().unwrap().trim_start_matches('/');
+        let paths: Vec<&str> = packed
+            .iter()
+            .map(|entry| entry.path.strip_prefix(prefix).unwrap())
+            .collect();
+        assert_equal!(
+            paths,
+            vec!["", "/Cargo.toml", "/src", "/src/empty", "/src/main.rs"]
+        );
+        assert_equal!(key.list_archive(archive).unwrap(), packed);
+        assert!(matches!(
+            Key::generate().list_archive(archive),
+            Err(Error::WrongKey)
+        ));
+
+        let unpacked = key.unpack_file(archive, &root.join("restored")).unwrap();
+        assert_equal!(unpacked, packed);
+        let restored = root.join("restored").join(prefix);
+        assert_equal!(
+            fs::read(restored.join("src/main.rs")).unwrap(),
+            b"fn main() {}"
+        );
+        assert!(restored.join("src/empty").is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(restored.join("src/main.rs"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_equal!(mode & 0o7777, 0o755);
+        }
+        let modified = fs::metadata(restored.join("Cargo.toml"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_equal!(modified, UNIX_EPOCH + Duration::new(1_600_000_000, 42));
+        assert!(key.unpack_file(archive, &root.join("restored")).is_err());
+
+        // an archive crafted with a path leaving the extraction directory
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap();
+        writer
+            .add_as(&project.join("Cargo.toml"), "aa/secret")
+            .unwrap();
+        let (mut plaintext, entries) = writer.finish().unwrap();
+        assert_equal!(entries[0].kind, EntryKind::File);
+        let position = plaintext
+            .windows(9)
+            .position(|window| window == b"aa/secret")
+            .unwrap();
+        plaintext[position..position + 2].copy_from_slice(b"..");
+        let crafted = root.join("crafted.aes");
+        fs::write(&crafted, key.encrypt(&plaintext).unwrap()).unwrap();
+        assert!(matches!(
+            key.unpack_file(crafted.to_str().unwrap(), &root.join("evil")),
+            Err(Error::Decode(_))
+        ));
+        assert!(!root.join("secret").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+/*!
+ASCII armor
+
+`armor` wraps a cyphertext in a PEM-style block that can be pasted into
+tickets and chat, with its base64 body broken into lines of 64 characters:
+
+```text
+-----BEGIN RUSTIC-TOOLZ MESSAGE-----
+cnRveno...
+-----END RUSTIC-TOOLZ MESSAGE-----
+```
+
+`ArmoredWriter` armors while streaming, and `open_file` reads armored and
+binary cyphertexts alike, as `open_reader` does for streams, which lets
+`Key::owns_file`, `Key::verify_file` and `Key::rotate_file` accept both.
+
+# Example
+
+```
+use toolz::aes256cbc::armor::{armor, dearmor, is_armored};
+use toolz::aes256cbc::Key;
+
+let key = Key::generate();
+let armored = armor(&key.encrypt(b"Pasted secret").unwrap());
+
+assert!(is_armored(armored.as_bytes()));
+let cyphertext = dearmor(armored.as_bytes()).unwrap();
+assert_eq!(key.decrypt(&cyphertext).unwrap(), b"Pasted secret".to_vec());
+```
+*/
+use super::{b64decode, b64encode, Error};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+
+///The first line of an armored message
+pub const ARMOR_BEGIN: &str = "-----BEGIN RUSTIC-TOOLZ MESSAGE-----";
+///The last line of an armored message
+pub const ARMOR_END: &str = "-----END RUSTIC-TOOLZ MESSAGE-----";
+///The number of bytes encoded in each full line, 64 base64 characters
+const LINE_BYTES: usize = 48;
+
+/// Armors a cyphertext
+pub fn armor(bytes: &[u8]) -> String {
+    let mut armored = format!("{}\n", ARMOR_BEGIN);
+    for line in bytes.chunks(LINE_BYTES) {
+        armored.push_str(&b64encode(line));
+        armored.push('\n');
+    }
+    armored.push_str(ARMOR_END);
+    armored.push('\n');
+    armored
+}
+
+/// Whether the given bytes start an armored message, ignoring leading
+/// whitespace
+pub fn is_armored(bytes: &[u8]) -> bool {
+    let start = bytes
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    bytes[start..].starts_with(ARMOR_BEGIN.as_bytes())
+}
+
+/// Decodes an armored message back to the cyphertext
+///
+/// Whitespace around the block and line endings are ignored, anything else
+/// outside of it fails with `Error::Decode`.
+pub fn dearmor(text: &[u8]) -> Result<Vec<u8>, Error> {
+    let text = std::str::from_utf8(text).map_err(Error::decode)?;
+    let mut lines = text.trim().lines().map(str::trim);
+    if lines.next() != Some(ARMOR_BEGIN) {
+        return Err(Error::decode("missing armor header"));
+    }
+    let mut body = String::new();
+    for line in lines.by_ref() {
+        if line == ARMOR_END {
+            if lines.next().is_some() {
+                return Err(Error::decode("data after armor footer"));
+            }
+            return b64decode(body.as_bytes());
+        }
+        body.push_str(line);
+    }
+    Err(Error::decode("missing armor footer"))
+}
+
+/// Opens a cyphertext file, decoding it first if it is armored
+pub fn open_file(filename: &str) -> Result<Box<dyn Read>, Error> {
+    open_reader(File::open(filename)?)
+}
+
+/// Reads a cyphertext from `inner`, decoding it first if it is armored
+pub fn open_reader<R: Read + 'static>(mut inner: R) -> Result<Box<dyn Read>, Error> {
+    let head = read_head(&mut inner)?;
+    let mut reader = io::Cursor::new(head).chain(BufReader::new(inner));
+    if is_armored(reader.get_ref().0.get_ref()) {
+        let mut text = Vec::new();
+        reader.read_to_end(&mut text)?;
+        return Ok(Box::new(io::Cursor::new(dearmor(&text)?)));
+    }
+    Ok(Box::new(reader))
+}
+
+/// Reads enough of `inner` to tell whether it starts with `ARMOR_BEGIN`
+/// after any whitespace, as pipes may return fewer bytes per read
+fn read_head<R: Read>(inner: &mut R) -> io::Result<Vec<u8>> {
+    let mut head: Vec<u8> = Vec::new();
+    let mut buffer = [0; ARMOR_BEGIN.len()];
+    loop {
+        if let Some(start) = head.iter().position(|byte| !byte.is_ascii_whitespace()) {
+            let marker = ARMOR_BEGIN.as_bytes();
+            let seen = &head[start..];
+            if seen.len() >= marker.len() || !marker.starts_with(seen) {
+                return Ok(head);
+            }
+        }
+        match inner.read(&mut buffer) {
+            Ok(0) => return Ok(head),
+            Ok(count) => head.extend_from_slice(&buffer[..count]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Armors everything written to it into `inner`
+///
+/// `finish` must be called to write the last line and the footer.
+pub struct ArmoredWriter<W: Write> {
+    inner: W,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> ArmoredWriter<W> {
+    /// Wraps `inner`, writing the header right away
+    pub fn new(mut inner: W) -> Result<ArmoredWriter<W>, Error> {
+        writeln!(inner, "{}", ARMOR_BEGIN)?;
+        Ok(ArmoredWriter {
+            inner,
+            pending: Vec::with_capacity(LINE_BYTES),
+        })
+    }
+    /// Writes the last line and the footer, returning the inner writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        if !self.pending.is_empty() {
+            writeln!(self.inner, "{}", b64encode(&self.pending))?;
+        }
+        writeln!(self.inner, "{}", ARMOR_END)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ArmoredWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let full = self.pending.len() - self.pending.len() % LINE_BYTES;
+        for line in self.pending[..full].chunks(LINE_BYTES) {
+            writeln!(self.inner, "{}", b64encode(line))?;
+        }
+        self.pending.drain(..full);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::armor::{armor, dearmor, open_reader, ArmoredWriter, ARMOR_BEGIN};
+    use crate::aes256cbc::{Error, Key};
+    use k9::assert_equal;
+    use std::io::{Read, Write};
+
+    /// A pipe returning one byte per read
+    struct Trickle(std::vec::IntoIter<u8>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match (buf.first_mut(), self.0.next()) {
+                (Some(slot), Some(byte)) => {
+                    *slot = byte;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_armored_writer_matches_armor() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut writer = ArmoredWriter::new(Vec::new()).unwrap();
+        for chunk in data.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        let armored = String::from_utf8(writer.finish().unwrap()).unwrap();
+
+        assert_equal!(armored, armor(&data));
+        assert!(armored.lines().all(|line| line.len() <= 64));
+        let pasted = format!("\n  {}\r\n", armored.replace('\n', "\r\n"));
+        assert_equal!(dearmor(pasted.as_bytes()).unwrap(), data);
+        assert!(matches!(
+            dearmor(format!("{}trailing", armored).as_bytes()),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_open_reader_dearmors_short_reads() {
+        let data: Vec<u8> = (0..=255).collect();
+        let armored = format!("\n{}", armor(&data)).into_bytes();
+        let mut decoded = Vec::new();
+        open_reader(Trickle(armored.into_iter()))
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_equal!(decoded, data);
+
+        let mut binary = Vec::new();
+        open_reader(Trickle(data.clone().into_iter()))
+            .unwrap()
+            .read_to_end(&mut binary)
+            .unwrap();
+        assert_equal!(binary, data);
+    }
+
+    #[test]
+    fn test_plaintext_starting_like_armor_is_not_owned() {
+        let filename = std::env::temp_dir().join(format!("armor-like-{}.txt", std::process::id()));
+        std::fs::write(&filename, format!("{}\nnot base64\n", ARMOR_BEGIN)).unwrap();
+        let owned = Key::generate().owns_file(filename.to_str().unwrap());
+        std::fs::remove_file(&filename).unwrap();
+        assert!(!owned.unwrap());
+    }
+}
@@ -0,0 +1,118 @@
+use crypto::symmetriccipher::SymmetricCipherError;
+use std::fmt;
+use std::io;
+
+/// The errors of the aes256cbc module
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or stream failed
+    Io(io::Error),
+    /// Malformed base64, YAML, key material or cyphertext header
+    Decode(String),
+    /// The data was not encrypted by the given key
+    WrongKey,
+    /// The authentication tag does not match: the data was tampered with or corrupted
+    Integrity,
+    /// The data carries no authentication tag, like legacy cyphertexts
+    /// using the key's own IV, so tampering cannot be detected
+    Unauthenticated,
+    /// The cipher failed, usually because of a bad length or padding
+    Cipher(SymmetricCipherError),
+    /// The key derivation parameters are invalid
+    Kdf(String),
+    /// The operation is not supported by the cipher of the key
+    Unsupported(String),
+    /// The passphrase of a protected key is wrong, or the key file is corrupted
+    Passphrase,
+    /// A key is missing from the keyring, already in it or badly named
+    Keyring(String),
+    /// Key shares are too few, repeated or from different splits
+    Shares(String),
+}
+
+impl Error {
+    pub fn decode<T: fmt::Display>(reason: T) -> Error {
+        Error::Decode(reason.to_string())
+    }
+    pub fn kdf<T: fmt::Display>(reason: T) -> Error {
+        Error::Kdf(reason.to_string())
+    }
+    pub fn unsupported<T: fmt::Display>(reason: T) -> Error {
+        Error::Unsupported(reason.to_string())
+    }
+    pub fn keyring<T: fmt::Display>(reason: T) -> Error {
+        Error::Keyring(reason.to_string())
+    }
+    pub fn shares<T: fmt::Display>(reason: T) -> Error {
+        Error::Shares(reason.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Decode(reason) => write!(f, "cannot decode: {}", reason),
+            Error::WrongKey => write!(f, "data was not encrypted by this key"),
+            Error::Integrity => write!(f, "data was tampered with or corrupted"),
+            Error::Unauthenticated => write!(f, "data carries no authentication tag"),
+            Error::Cipher(error) => write!(f, "cipher error: {:?}", error),
+            Error::Kdf(reason) => write!(f, "key derivation failed: {}", reason),
+            Error::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            Error::Passphrase => write!(f, "wrong passphrase or corrupted key file"),
+            Error::Keyring(reason) => write!(f, "keyring: {}", reason),
+            Error::Shares(reason) => write!(f, "shares: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Unwraps errors of this module that went through `std::io` adapters
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let kind = error.kind();
+            return match error.into_inner().map(|inner| inner.downcast::<Error>()) {
+                Some(Ok(inner)) => *inner,
+                _ => Error::Io(io::Error::from(kind)),
+            };
+        }
+        Error::Io(error)
+    }
+}
+
+/// Lets errors of this module go through `std::io::Read` and `std::io::Write`
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+impl From<SymmetricCipherError> for Error {
+    fn from(error: SymmetricCipherError) -> Error {
+        Error::Cipher(error)
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(error: base64::DecodeError) -> Error {
+        Error::decode(error)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(error: serde_yaml::Error) -> Error {
+        Error::decode(error)
+    }
+}
@@ -0,0 +1,662 @@
+/*!
+Cyphertext container format
+
+Everything written by `Key::encrypt` and `EncryptingWriter` starts with a
+self-describing header, so that a file can be decrypted without knowing the
+`Config` that derived its key. All integers are big-endian.
+
+| field          | size          | description                                      |
+|----------------|---------------|--------------------------------------------------|
+| magic          | 8             | `TOOLZENC`                                       |
+| version        | 1             | format version, `1`, or `2` with recipients      |
+| cipher         | 1             | `1`: AES-256/CBC/Pkcs + HMAC-SHA256, `2`: AES-256-GCM, `3`: ChaCha20-Poly1305, `4`: XChaCha20-Poly1305 |
+| kdf            | 1             | `0`: random key, `1`: PBKDF2-HMAC-SHA256, `2`: scrypt, `3`: Argon2id |
+| kdf params     | 1 + 4 × count | count followed by the `u32` parameters           |
+| salt           | 1 + length    | length followed by the random salt of the key, empty for random and legacy keys |
+| iv             | 1 + length    | length followed by the IV or nonce of this message |
+| key id         | 32            | `Key::key_id()` of the key that encrypted it     |
+| recipients     | 1 + slots     | version `2` only: count followed by the recipient slots |
+| cyphertext     | ...           |                                                  |
+| tag            | 32            | HMAC-SHA256 of everything before it              |
+
+Each recipient slot is the 32 bytes `Key::key_id()` of a recipient key, then
+the length and nonce, then the length and wrapped material of the random
+data key that encrypted the message, see the `recipients` module.
+
+With the AEAD ciphers there is no trailing HMAC tag: the cyphertext is made
+of chunks that each carry their own tag, see the `aead` module.
+
+The PBKDF2 parameters are the key, salt and iv cycles. The scrypt parameters
+are the same cycles, which still derive the salt and iv, followed by `log_n`,
+`r` and `p`. The Argon2id parameters are the cycles followed by the memory,
+time and parallelism costs.
+
+Cyphertexts written before the container format have no magic and begin
+with a 32 bytes key digest instead: `Key::random_iv_digest()` followed by the
+IV and ending with the tag, or the legacy `Key::digest()` followed by data
+encrypted with the IV of the key and no tag. The former are still
+decrypted, the latter only when asked for with
+`DecryptingReader::with_unauthenticated_legacy`, as nothing tells them
+apart from data forged with the digest of the key.
+*/
+use super::{
+    aead, bytes_match, Argon2Config, Config, CyclesConfig, Error, KdfConfig, Key, ScryptConfig,
+    ALGO, DIGEST_SIZE, IV_SIZE,
+};
+use rand::RngCore;
+use std::io::Read;
+
+///The first bytes of every container
+pub const MAGIC: &[u8; 8] = b"TOOLZENC";
+///The current container format version
+pub const FORMAT_VERSION: u8 = 1;
+///The container format version with recipient slots
+pub const RECIPIENTS_FORMAT_VERSION: u8 = 2;
+
+///The most PBKDF2 cycles accepted from a header
+const MAX_CYCLES: u32 = 10_000_000;
+///The largest scrypt cost accepted from a header, as a power of two
+const MAX_SCRYPT_LOG_N: u32 = 20;
+///The most memory, `128 × r × 2^log_n` bytes, of the scrypt parameters
+///accepted from a header
+const MAX_SCRYPT_MEMORY: u64 = 1 << 30;
+///The largest scrypt parallelism accepted from a header
+const MAX_SCRYPT_P: u32 = 16;
+///The largest Argon2id memory cost, in KiB, accepted from a header
+const MAX_ARGON2_MEMORY: u32 = 1 << 20;
+///The most Argon2id passes accepted from a header
+const MAX_ARGON2_TIME: u32 = 16;
+///The largest Argon2id parallelism accepted from a header
+const MAX_ARGON2_PARALLELISM: u32 = 16;
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, Error> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_sized<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0; read_u8(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The cipher used for the data following the header
+#[derive(Clone, Copy, PartialEq)]
+pub enum Cipher {
+    Aes256CbcHmacSha256,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn id(&self) -> u8 {
+        match self {
+            Cipher::Aes256CbcHmacSha256 => 1,
+            Cipher::Aes256Gcm => 2,
+            Cipher::ChaCha20Poly1305 => 3,
+            Cipher::XChaCha20Poly1305 => 4,
+        }
+    }
+    pub fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::Aes256CbcHmacSha256),
+            2 => Some(Cipher::Aes256Gcm),
+            3 => Some(Cipher::ChaCha20Poly1305),
+            4 => Some(Cipher::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+    /// The name of the cipher in the `algo` field of a `Key`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cipher::Aes256CbcHmacSha256 => ALGO,
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+            Cipher::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+    /// Every supported cipher
+    pub fn all() -> [Cipher; 4] {
+        [
+            Cipher::Aes256CbcHmacSha256,
+            Cipher::Aes256Gcm,
+            Cipher::ChaCha20Poly1305,
+            Cipher::XChaCha20Poly1305,
+        ]
+    }
+    pub fn from_name(name: &str) -> Option<Cipher> {
+        Cipher::all()
+            .into_iter()
+            .find(|cipher| cipher.name() == name)
+    }
+    /// Whether the cipher authenticates chunks itself, see the `aead` module
+    pub fn is_aead(&self) -> bool {
+        *self != Cipher::Aes256CbcHmacSha256
+    }
+    /// The size of the IV, or nonce, of a message
+    pub fn iv_size(&self) -> usize {
+        match self {
+            Cipher::Aes256CbcHmacSha256 => IV_SIZE,
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => aead::NONCE_SIZE,
+            Cipher::XChaCha20Poly1305 => aead::XNONCE_SIZE,
+        }
+    }
+    /// Generates a random IV, or nonce, for a message
+    pub fn generate_iv(&self) -> Vec<u8> {
+        let mut iv = vec![0; self.iv_size()];
+        rand::thread_rng().fill_bytes(&mut iv);
+        iv
+    }
+}
+
+/// How the key that encrypted the data was obtained
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kdf {
+    /// A random key, see `Key::generate`
+    None,
+    /// A key derived from a password, see `Key::from_password`
+    Pbkdf2HmacSha256(CyclesConfig),
+    /// A key derived from a password with scrypt, see `KdfConfig::Scrypt`
+    Scrypt(CyclesConfig, ScryptConfig),
+    /// A key derived from a password with Argon2id, see `KdfConfig::Argon2id`
+    Argon2id(CyclesConfig, Argon2Config),
+}
+
+impl Kdf {
+    pub fn id(&self) -> u8 {
+        match self {
+            Kdf::None => 0,
+            Kdf::Pbkdf2HmacSha256(_) => 1,
+            Kdf::Scrypt(_, _) => 2,
+            Kdf::Argon2id(_, _) => 3,
+        }
+    }
+    pub fn params(&self) -> Vec<u32> {
+        match self {
+            Kdf::None => Vec::new(),
+            Kdf::Pbkdf2HmacSha256(cycles) => cycles.to_vec(),
+            Kdf::Scrypt(cycles, scrypt) => {
+                let mut params = cycles.to_vec();
+                params.extend_from_slice(&[scrypt.log_n as u32, scrypt.r, scrypt.p]);
+                params
+            }
+            Kdf::Argon2id(cycles, argon2) => {
+                let mut params = cycles.to_vec();
+                params.extend_from_slice(&[argon2.memory, argon2.time, argon2.parallelism]);
+                params
+            }
+        }
+    }
+    /// Parses the kdf of a header
+    ///
+    /// Fails with `Error::Unsupported` on parameters costly enough to
+    /// exhaust the CPU or memory before anything is authenticated.
+    pub fn from_params(id: u8, params: &[u32]) -> Result<Kdf, Error> {
+        let kdf = match (id, params) {
+            (0, []) => Kdf::None,
+            (1, [key, salt, iv]) => {
+                Kdf::Pbkdf2HmacSha256(CyclesConfig::from_vec(&[*key, *salt, *iv]))
+            }
+            (2, [key, salt, iv, log_n, r, p]) if *log_n < 64 => Kdf::Scrypt(
+                CyclesConfig::from_vec(&[*key, *salt, *iv]),
+                ScryptConfig {
+                    log_n: *log_n as u8,
+                    r: *r,
+                    p: *p,
+                },
+            ),
+            (3, [key, salt, iv, memory, time, parallelism]) => Kdf::Argon2id(
+                CyclesConfig::from_vec(&[*key, *salt, *iv]),
+                Argon2Config {
+                    memory: *memory,
+                    time: *time,
+                    parallelism: *parallelism,
+                },
+            ),
+            _ => return Err(Error::decode("unsupported kdf")),
+        };
+        kdf.check_cost()?;
+        Ok(kdf)
+    }
+    fn check_cost(&self) -> Result<(), Error> {
+        let cycles = match self {
+            Kdf::None => return Ok(()),
+            Kdf::Pbkdf2HmacSha256(cycles) | Kdf::Scrypt(cycles, _) | Kdf::Argon2id(cycles, _) => {
+                cycles
+            }
+        };
+        if cycles.to_vec().into_iter().any(|count| count > MAX_CYCLES) {
+            return Err(Error::unsupported(format!(
+                "more than {} PBKDF2 cycles",
+                MAX_CYCLES
+            )));
+        }
+        if let Kdf::Scrypt(_, scrypt) = self {
+            let log_n = scrypt.log_n as u32;
+            if log_n > MAX_SCRYPT_LOG_N
+                || (128 * scrypt.r as u64) << log_n > MAX_SCRYPT_MEMORY
+                || scrypt.p > MAX_SCRYPT_P
+            {
+                return Err(Error::unsupported(format!(
+                    "scrypt parameters above log_n {}, {} bytes of memory or p {}",
+                    MAX_SCRYPT_LOG_N, MAX_SCRYPT_MEMORY, MAX_SCRYPT_P
+                )));
+            }
+        }
+        if let Kdf::Argon2id(_, argon2) = self {
+            if argon2.memory > MAX_ARGON2_MEMORY
+                || argon2.time > MAX_ARGON2_TIME
+                || argon2.parallelism > MAX_ARGON2_PARALLELISM
+            {
+                return Err(Error::unsupported(format!(
+                    "Argon2id parameters above {} KiB of memory, {} passes or {} lanes",
+                    MAX_ARGON2_MEMORY, MAX_ARGON2_TIME, MAX_ARGON2_PARALLELISM
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// The kdf recorded in a key
+    pub fn of_key(key: &Key) -> Kdf {
+        match key.magic {
+            Some(ref magic) if magic.len() == 3 => {
+                let cycles = CyclesConfig::from_vec(&[magic[0], magic[1], magic[2]]);
+                match key.kdf {
+                    Some(KdfConfig::Scrypt(scrypt)) => Kdf::Scrypt(cycles, scrypt),
+                    Some(KdfConfig::Argon2id(argon2)) => Kdf::Argon2id(cycles, argon2),
+                    _ => Kdf::Pbkdf2HmacSha256(cycles),
+                }
+            }
+            _ => Kdf::None,
+        }
+    }
+}
+
+/// The data key of a multi-recipient container, wrapped for one recipient
+#[derive(Clone, PartialEq)]
+pub struct Recipient {
+    /// `Key::key_id()` of the recipient key
+    pub key_id: [u8; DIGEST_SIZE],
+    pub nonce: Vec<u8>,
+    pub wrapped: Vec<u8>,
+}
+
+impl Recipient {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.key_id.to_vec();
+        bytes.push(self.nonce.len() as u8);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.push(self.wrapped.len() as u8);
+        bytes.extend_from_slice(&self.wrapped);
+        bytes
+    }
+    fn read_from<R: Read>(reader: &mut R) -> Result<Recipient, Error> {
+        let mut key_id = [0; DIGEST_SIZE];
+        reader.read_exact(&mut key_id)?;
+        Ok(Recipient {
+            key_id,
+            nonce: read_sized(reader)?,
+            wrapped: read_sized(reader)?,
+        })
+    }
+}
+
+/// The header of a container
+#[derive(Clone, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    pub iv: Vec<u8>,
+    pub key_id: [u8; DIGEST_SIZE],
+    /// The recipient slots, empty unless the version is `RECIPIENTS_FORMAT_VERSION`
+    pub recipients: Vec<Recipient>,
+}
+
+impl Header {
+    /// Describes a message encrypted by `key` with the given IV
+    pub fn new(key: &Key, iv: &[u8]) -> Result<Header, Error> {
+        let cipher = key.cipher()?;
+        if iv.len() != cipher.iv_size() {
+            return Err(Error::decode("invalid IV size"));
+        }
+        let salt = key.salt_bytes()?;
+        if salt.len() > u8::MAX as usize {
+            return Err(Error::decode("salt too long"));
+        }
+        Ok(Header {
+            version: FORMAT_VERSION,
+            cipher,
+            kdf: Kdf::of_key(key),
+            salt,
+            iv: iv.to_vec(),
+            key_id: key.key_id()?,
+            recipients: Vec::new(),
+        })
+    }
+    /// Records the data key wrapped for each recipient
+    pub fn with_recipients(self, recipients: Vec<Recipient>) -> Result<Header, Error> {
+        if recipients.is_empty() || recipients.len() > u8::MAX as usize {
+            return Err(Error::decode("invalid number of recipients"));
+        }
+        Ok(Header {
+            version: RECIPIENTS_FORMAT_VERSION,
+            recipients,
+            ..self
+        })
+    }
+    /// Serializes the header, magic included
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let params = self.kdf.params();
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.cipher.id());
+        bytes.push(self.kdf.id());
+        bytes.push(params.len() as u8);
+        for param in params {
+            bytes.extend_from_slice(&param.to_be_bytes());
+        }
+        bytes.push(self.salt.len() as u8);
+        bytes.extend_from_slice(&self.salt);
+        bytes.push(self.iv.len() as u8);
+        bytes.extend_from_slice(&self.iv);
+        bytes.extend_from_slice(&self.key_id);
+        if self.version == RECIPIENTS_FORMAT_VERSION {
+            bytes.push(self.recipients.len() as u8);
+            for recipient in &self.recipients {
+                bytes.extend(recipient.to_bytes());
+            }
+        }
+        bytes
+    }
+    /// Parses the header following the magic
+    fn read_after_magic<R: Read>(reader: &mut R) -> Result<Header, Error> {
+        let version = read_u8(reader)?;
+        if version != FORMAT_VERSION && version != RECIPIENTS_FORMAT_VERSION {
+            return Err(Error::decode("unsupported container format version"));
+        }
+        let cipher =
+            Cipher::from_id(read_u8(reader)?).ok_or_else(|| Error::decode("unsupported cipher"))?;
+        let kdf_id = read_u8(reader)?;
+        let mut params = Vec::new();
+        for _ in 0..read_u8(reader)? {
+            let mut param = [0; 4];
+            reader.read_exact(&mut param)?;
+            params.push(u32::from_be_bytes(param));
+        }
+        let kdf = Kdf::from_params(kdf_id, &params)?;
+        let salt = read_sized(reader)?;
+        let iv = read_sized(reader)?;
+        if iv.len() != cipher.iv_size() {
+            return Err(Error::decode("invalid IV size"));
+        }
+        let mut key_id = [0; DIGEST_SIZE];
+        reader.read_exact(&mut key_id)?;
+        let mut recipients = Vec::new();
+        if version == RECIPIENTS_FORMAT_VERSION {
+            for _ in 0..read_u8(reader)? {
+                recipients.push(Recipient::read_from(reader)?);
+            }
+        }
+        Ok(Header {
+            version,
+            cipher,
+            kdf,
+            salt,
+            iv,
+            key_id,
+            recipients,
+        })
+    }
+    /// Whether the container was written with the key of the given id and
+    /// cipher, or has a recipient slot for it
+    pub fn is_for(&self, key_id: &[u8], cipher: Cipher) -> bool {
+        if self.version == RECIPIENTS_FORMAT_VERSION {
+            return self
+                .recipients
+                .iter()
+                .any(|recipient| bytes_match(&recipient.key_id, key_id));
+        }
+        bytes_match(&self.key_id, key_id) && self.cipher == cipher
+    }
+    /// The config that derives the key from its password, if any
+    pub fn config(&self) -> Option<Config> {
+        match self.kdf {
+            Kdf::None => None,
+            Kdf::Pbkdf2HmacSha256(cycles) => {
+                Some(Config::from_vec(&[cycles.key, cycles.salt, cycles.iv]))
+            }
+            Kdf::Scrypt(cycles, scrypt) => Some(
+                Config::from_vec(&[cycles.key, cycles.salt, cycles.iv])
+                    .with_kdf(KdfConfig::Scrypt(scrypt)),
+            ),
+            Kdf::Argon2id(cycles, argon2) => Some(
+                Config::from_vec(&[cycles.key, cycles.salt, cycles.iv])
+                    .with_kdf(KdfConfig::Argon2id(argon2)),
+            ),
+        }
+    }
+}
+
+/// The beginning of a cyphertext in any of the supported layouts
+pub enum Envelope {
+    /// A container header
+    Container(Header),
+    /// The 32 bytes key digest of cyphertexts written before containers
+    Digest([u8; DIGEST_SIZE]),
+}
+
+impl Envelope {
+    /// Reads the header or the leading digest of a cyphertext
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Envelope, Error> {
+        let mut digest = [0; DIGEST_SIZE];
+        reader.read_exact(&mut digest[..MAGIC.len()])?;
+        if &digest[..MAGIC.len()] == MAGIC {
+            return Ok(Envelope::Container(Header::read_after_magic(reader)?));
+        }
+        reader.read_exact(&mut digest[MAGIC.len()..])?;
+        Ok(Envelope::Digest(digest))
+    }
+    /// The bytes the envelope was read from
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Envelope::Container(header) => header.to_bytes(),
+            Envelope::Digest(digest) => digest.to_vec(),
+        }
+    }
+}
+
+/// What a key needs to decrypt the data following an envelope
+pub(crate) struct Opened {
+    /// The key that decrypts the data: the opening key itself, or
+    /// the data key it unwrapped from a recipient slot
+    pub key: Key,
+    pub cipher: Cipher,
+    pub iv: Vec<u8>,
+    /// The bytes read so far that are covered by the tag,
+    /// `None` for legacy cyphertexts without a tag
+    pub authenticated: Option<Vec<u8>>,
+}
+
+impl Key {
+    /// Checks an envelope against this key, reading the IV from `reader`
+    /// when it follows the digest. Fails with `Error::WrongKey` if the
+    /// cyphertext was encrypted by another key, and with
+    /// `Error::Unauthenticated` on legacy cyphertexts without a tag unless
+    /// `allow_unauthenticated` is set.
+    pub(crate) fn open<R: Read>(
+        &self,
+        envelope: &Envelope,
+        reader: &mut R,
+        allow_unauthenticated: bool,
+    ) -> Result<Opened, Error> {
+        let legacy = self.cipher()? == Cipher::Aes256CbcHmacSha256;
+        match envelope {
+            Envelope::Container(header) if self.owns_header(header)? => Ok(Opened {
+                key: self.container_key(header)?,
+                cipher: header.cipher,
+                iv: header.iv.clone(),
+                authenticated: Some(header.to_bytes()),
+            }),
+            Envelope::Digest(digest) if legacy && self.has_random_iv(digest)? => {
+                let mut iv = [0; IV_SIZE];
+                reader.read_exact(&mut iv)?;
+                Ok(Opened {
+                    key: self.clone(),
+                    cipher: Cipher::Aes256CbcHmacSha256,
+                    iv: iv.to_vec(),
+                    authenticated: Some([&digest[..], &iv].concat()),
+                })
+            }
+            Envelope::Digest(digest) if legacy && self.check_digest(digest)? => {
+                if !allow_unauthenticated {
+                    return Err(Error::Unauthenticated);
+                }
+                Ok(Opened {
+                    key: self.clone(),
+                    cipher: Cipher::Aes256CbcHmacSha256,
+                    iv: self.iv_bytes()?,
+                    authenticated: None,
+                })
+            }
+            _ => Err(Error::WrongKey),
+        }
+    }
+    /// Checks if a container header was written with this key and its cipher,
+    /// or has a recipient slot for this key
+    pub fn owns_header(&self, header: &Header) -> Result<bool, Error> {
+        Ok(header.is_for(&self.key_id()?, self.cipher()?))
+    }
+    /// Checks if an envelope was written with this key
+    ///
+    /// Only AES-256/CBC keys wrote cyphertexts before containers.
+    pub fn owns_envelope(&self, envelope: &Envelope) -> Result<bool, Error> {
+        match envelope {
+            Envelope::Container(header) => self.owns_header(header),
+            Envelope::Digest(digest) => {
+                Ok(self.cipher()? == Cipher::Aes256CbcHmacSha256 && self.check_digest(digest)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::format::{Envelope, Header, Kdf};
+    use crate::aes256cbc::{
+        generate_salt, Argon2Config, Config, CyclesConfig, Error, KdfConfig, Key, ScryptConfig,
+    };
+    use k9::assert_equal;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let key = Key::from_password(
+            b"123456",
+            &generate_salt(),
+            &Config::from_vec(&[10, 20, 30]),
+        )
+        .unwrap();
+        let header = Header::new(&key, &[9; 16]).unwrap();
+        let bytes = header.to_bytes();
+
+        match Envelope::read_from(&mut bytes.as_slice()).unwrap() {
+            Envelope::Container(parsed) => {
+                assert!(parsed == header);
+                assert!(parsed.kdf == Kdf::of_key(&key));
+                assert!(parsed.config().unwrap().cycles == Config::from_vec(&[10, 20, 30]).cycles);
+            }
+            Envelope::Digest(_) => panic!("expected a container"),
+        }
+    }
+
+    #[test]
+    fn test_password_key_derived_from_header_decrypts() {
+        let key = Key::from_password(
+            b"123456",
+            &generate_salt(),
+            &Config::from_vec(&[10, 20, 30]),
+        )
+        .unwrap();
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+
+        let header = match Envelope::read_from(&mut cyphertext.as_slice()).unwrap() {
+            Envelope::Container(header) => header,
+            Envelope::Digest(_) => panic!("expected a container"),
+        };
+        let config = header.config().unwrap();
+        let derived = Key::from_password(b"123456", &header.salt, &config).unwrap();
+        assert_equal!(derived.decrypt(&cyphertext).unwrap(), b"This is a secret");
+    }
+
+    #[test]
+    fn test_scrypt_config_recorded_in_header() {
+        let scrypt = KdfConfig::Scrypt(ScryptConfig {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        });
+        let config = Config::from_vec(&[10, 20, 30]).with_kdf(scrypt);
+        let key = Key::from_password(b"123456", &generate_salt(), &config).unwrap();
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+
+        let header = match Envelope::read_from(&mut cyphertext.as_slice()).unwrap() {
+            Envelope::Container(header) => header,
+            Envelope::Digest(_) => panic!("expected a container"),
+        };
+        let config = header.config().unwrap();
+        assert!(config.kdf == scrypt);
+        let derived = Key::from_password(b"123456", &header.salt, &config).unwrap();
+        assert_equal!(derived.decrypt(&cyphertext).unwrap(), b"This is a secret");
+    }
+
+    #[test]
+    fn test_header_with_costly_kdf_is_rejected() {
+        let key = Key::generate();
+        let mut header = Header::new(&key, &key.iv_bytes().unwrap()).unwrap();
+        let cycles = CyclesConfig::from_vec(&[10, 20, 30]);
+        let scrypt = ScryptConfig {
+            log_n: 30,
+            r: 8,
+            p: 1,
+        };
+        for kdf in [
+            Kdf::Pbkdf2HmacSha256(CyclesConfig::from_vec(&[10, u32::MAX, 30])),
+            Kdf::Scrypt(cycles, scrypt),
+            Kdf::Scrypt(
+                cycles,
+                ScryptConfig {
+                    log_n: 15,
+                    r: 1024,
+                    p: 1,
+                },
+            ),
+            Kdf::Argon2id(
+                cycles,
+                Argon2Config {
+                    memory: u32::MAX,
+                    time: 2,
+                    parallelism: 1,
+                },
+            ),
+            Kdf::Argon2id(
+                cycles,
+                Argon2Config {
+                    memory: 19 * 1024,
+                    time: u32::MAX,
+                    parallelism: 1,
+                },
+            ),
+        ] {
+            header.kdf = kdf;
+            let bytes = header.to_bytes();
+            assert!(matches!(
+                Envelope::read_from(&mut bytes.as_slice()),
+                Err(Error::Unsupported(_))
+            ));
+        }
+    }
+}
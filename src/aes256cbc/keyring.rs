@@ -0,0 +1,240 @@
+/*!
+Local keyring
+
+A keyring is a directory of key files, `~/.rustic-toolz/keys/` unless
+another path is given, where each key is named after its file without the
+`.yaml` extension. `Keyring::owner_of` finds the key that encrypted a file,
+so that it can be decrypted without naming its key.
+
+The directory and the key files added to it are only readable by their owner.
+*/
+use super::format::Envelope;
+use super::{read_envelope, Error, Key};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+///The directory of the keyring of the current user
+pub const DEFAULT_KEYRING_PATH: &str = "~/.rustic-toolz/keys";
+///The extension of the key files in a keyring
+const KEY_EXTENSION: &str = "yaml";
+
+/// A directory of named key files
+pub struct Keyring {
+    path: PathBuf,
+}
+
+impl Keyring {
+    /// The keyring in the given directory, which is created by `add`
+    pub fn open(path: &str) -> Keyring {
+        Keyring {
+            path: PathBuf::from(shellexpand::tilde(path).as_ref()),
+        }
+    }
+    /// The keyring in `DEFAULT_KEYRING_PATH`
+    pub fn user() -> Keyring {
+        Keyring::open(DEFAULT_KEYRING_PATH)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// The path of the key file with the given name, which must be a
+    /// plain file name
+    pub fn key_path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(Error::keyring(format!("invalid key name: {}", name)));
+        }
+        Ok(self.path.join(format!("{}.{}", name, KEY_EXTENSION)))
+    }
+    /// The sorted names of the keys, none if the directory does not exist
+    pub fn names(&self) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == KEY_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+    /// Reads a key without unwrapping it if it is protected
+    pub fn get(&self, name: &str) -> Result<Key, Error> {
+        match fs::read_to_string(self.key_path(name)?) {
+            Ok(yaml) => Key::from_yaml(yaml),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(Error::keyring(format!("no such key: {}", name)))
+            }
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+    /// Loads a key, asking for its passphrase if it is protected,
+    /// see `Key::import`
+    pub fn import(&self, name: &str) -> Result<Key, Error> {
+        self.get(name)?;
+        Key::import(&self.key_path(name)?.to_string_lossy())
+    }
+    /// Copies a key file into the keyring under the given name
+    pub fn add(&self, name: &str, filename: &str) -> Result<PathBuf, Error> {
+        let path = self.key_path(name)?;
+        let yaml = fs::read_to_string(filename)?;
+        Key::from_yaml(yaml.clone())?.cipher()?;
+        create_private_dir(&self.path)?;
+        match write_private_file(&path, &yaml) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Error::keyring(format!("key already exists: {}", name)))
+            }
+            result => Ok(result.map(|_| path)?),
+        }
+    }
+    /// Deletes the key file with the given name
+    pub fn remove(&self, name: &str) -> Result<PathBuf, Error> {
+        let path = self.key_path(name)?;
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(Error::keyring(format!("no such key: {}", name)))
+            }
+            result => Ok(result.map(|_| path)?),
+        }
+    }
+    /// Finds the key that encrypted a file, trying the unprotected keys
+    /// before asking for the passphrase of the protected ones
+    ///
+    /// Key files that cannot be read are skipped, and so are the protected
+    /// keys that cannot own the file according to `Key::may_own_envelope`
+    /// or whose passphrase is wrong, which lets an empty one skip a key.
+    pub fn owner_of(&self, filename: &str) -> Result<Option<(String, Key)>, Error> {
+        match read_envelope(filename)? {
+            Some(envelope) => self.owner_of_envelope(&envelope),
+            None => Ok(None),
+        }
+    }
+    /// Finds the key that wrote an envelope, like `owner_of`
+    pub fn owner_of_envelope(&self, envelope: &Envelope) -> Result<Option<(String, Key)>, Error> {
+        let mut protected = Vec::new();
+        for name in self.names()? {
+            let key = match self.get(&name) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if key.is_protected() {
+                if key.may_own_envelope(envelope)? {
+                    protected.push(name);
+                }
+            } else if key.owns_envelope(envelope)? {
+                return Ok(Some((name, key)));
+            }
+        }
+        for name in protected {
+            let key = match self.import(&name) {
+                Ok(key) => key,
+                Err(Error::Passphrase) => continue,
+                Err(e) => return Err(e),
+            };
+            if key.owns_envelope(envelope)? {
+                return Ok(Some((name, key)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)
+}
+
+pub(super) fn write_private_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::format::Envelope;
+    use crate::aes256cbc::keyring::Keyring;
+    use crate::aes256cbc::{Config, Error, Key};
+    use k9::assert_equal;
+
+    #[test]
+    fn test_keyring_finds_the_owner_of_a_file() {
+        let directory = std::env::temp_dir().join(format!("keyring-{}", std::process::id()));
+        let keyring = Keyring::open(directory.to_str().unwrap());
+        assert_equal!(keyring.names().unwrap(), Vec::<String>::new());
+
+        let (alice, bob) = (Key::generate(), Key::generate());
+        for (name, key) in [("alice", &alice), ("bob", &bob)] {
+            let filename = directory.with_extension(name);
+            key.export(filename.to_str().unwrap()).unwrap();
+            keyring.add(name, filename.to_str().unwrap()).unwrap();
+            std::fs::remove_file(filename).unwrap();
+        }
+        assert_equal!(keyring.names().unwrap(), vec!["alice", "bob"]);
+        assert!(matches!(
+            keyring.add("bob", keyring.key_path("alice").unwrap().to_str().unwrap()),
+            Err(Error::Keyring(_))
+        ));
+        assert!(matches!(keyring.key_path("../bob"), Err(Error::Keyring(_))));
+
+        let encrypted = directory.with_extension("aes");
+        let filename = encrypted.to_str().unwrap();
+        std::fs::write(filename, bob.encrypt(b"This is a secret").unwrap()).unwrap();
+        let (name, key) = keyring.owner_of(filename).unwrap().unwrap();
+        assert_equal!(name, "bob");
+        assert!(key == bob);
+
+        keyring.remove("bob").unwrap();
+        assert!(keyring.owner_of(filename).unwrap().is_none());
+        std::fs::remove_file(filename).unwrap();
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_keyring_does_not_unlock_protected_keys_that_cannot_own_a_file() {
+        let directory =
+            std::env::temp_dir().join(format!("keyring-protected-{}", std::process::id()));
+        let keyring = Keyring::open(directory.to_str().unwrap());
+        let carol = Key::generate();
+        let protected = carol
+            .protect(b"passphrase", &Config::from_vec(&[10, 20, 30]))
+            .unwrap();
+        let filename = directory.with_extension("carol");
+        protected.export(filename.to_str().unwrap()).unwrap();
+        keyring.add("carol", filename.to_str().unwrap()).unwrap();
+        std::fs::remove_file(filename).unwrap();
+        let protected = keyring.get("carol").unwrap();
+        assert!(protected.is_protected());
+
+        let own = carol.encrypt(b"This is a secret").unwrap();
+        let own = Envelope::read_from(&mut own.as_slice()).unwrap();
+        assert!(protected.may_own_envelope(&own).unwrap());
+
+        // no passphrase is asked for a file of another key
+        let encrypted = directory.with_extension("aes");
+        let filename = encrypted.to_str().unwrap();
+        std::fs::write(filename, Key::generate().encrypt(b"Not hers").unwrap()).unwrap();
+        assert!(keyring.owner_of(filename).unwrap().is_none());
+        std::fs::remove_file(filename).unwrap();
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
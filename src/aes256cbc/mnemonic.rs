@@ -0,0 +1,94 @@
+/*!
+Keys derived from a BIP39 mnemonic
+
+`Key::from_mnemonic` recreates a key from a phrase written down on paper,
+such as the ones printed by the `bip39` binary, and an optional
+passphrase. The BIP39 seed of the phrase is stretched with the key
+derivation function of a `Config` and split into the cipher key, the mac
+key and the IV with HKDF-SHA256.
+
+The same phrase, passphrase and config always give the same key.
+
+# Example
+
+```
+use toolz::aes256cbc::{Config, Key};
+
+let phrase = "park remain person kitchen mule spell knee armed position rail grid ankle";
+let config = Config::from_vec(&[100, 200, 300]);
+let key = Key::from_mnemonic(phrase, "", &config).unwrap();
+
+assert!(Key::from_mnemonic(phrase, "", &config).unwrap() == key);
+assert!(Key::from_mnemonic(phrase, "TREZOR", &config).unwrap() != key);
+```
+*/
+use super::{b64encode, timestamp, Config, Error, Key, ALGO, CIPHER_KEY_SIZE, IV_SIZE, KEY_SIZE};
+use bip39::{Language, Mnemonic, Seed};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use zeroize::Zeroizing;
+
+///The salt of the key derivation of mnemonic keys, which must be fixed
+///for a phrase to recreate its key
+const MNEMONIC_SALT: &[u8] = b"rustic-toolz aes256cbc mnemonic";
+///The HKDF info of the cipher key, mac key and IV
+const MNEMONIC_KEY_INFO: &[u8] = b"aes256cbc mnemonic key";
+
+impl Key {
+    /// Derive a key from a BIP39 mnemonic phrase, validated against its
+    /// checksum, and a passphrase that may be empty
+    ///
+    /// Fails with `Error::Decode` if the phrase is not a valid English
+    /// BIP39 mnemonic.
+    pub fn from_mnemonic(phrase: &str, passphrase: &str, config: &Config) -> Result<Key, Error> {
+        let phrase = Zeroizing::new(phrase.to_lowercase());
+        let mnemonic = Mnemonic::from_phrase(&phrase, Language::English)
+            .map_err(|error| Error::decode(format!("invalid mnemonic: {}", error)))?;
+        let seed = Seed::new(&mnemonic, passphrase);
+        let stretched = config.derive_key(seed.as_bytes(), MNEMONIC_SALT)?;
+
+        let mut prk = Zeroizing::new([0u8; 32]);
+        hkdf_extract(Sha256::new(), MNEMONIC_SALT, &stretched[..], &mut *prk);
+        let mut material = Zeroizing::new([0u8; KEY_SIZE + IV_SIZE]);
+        hkdf_expand(Sha256::new(), &prk[..], MNEMONIC_KEY_INFO, &mut *material);
+
+        Ok(Key {
+            key: b64encode(&material[..CIPHER_KEY_SIZE]),
+            mac: b64encode(&material[CIPHER_KEY_SIZE..KEY_SIZE]),
+            iv: b64encode(&material[KEY_SIZE..]),
+            algo: String::from(ALGO),
+            magic: Some(config.cycles.to_vec()),
+            kdf: Some(config.kdf),
+            salt: None,
+            protected: None,
+            created: Some(timestamp()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::{Config, Error, Key};
+    use k9::assert_equal;
+
+    const PHRASE: &str =
+        "park remain person kitchen mule spell knee armed position rail grid ankle";
+
+    #[test]
+    fn test_mnemonic_recreates_the_key() {
+        let config = Config::from_vec(&[100, 200, 300]);
+        let key = Key::from_mnemonic(PHRASE, "paper", &config).unwrap();
+        let cyphertext = key.encrypt(b"Paper backup").unwrap();
+
+        let written_down = format!("  {}\n", PHRASE.to_uppercase().replace(' ', "   "));
+        let recreated = Key::from_mnemonic(&written_down, "paper", &config).unwrap();
+        assert_equal!(recreated.decrypt(&cyphertext).unwrap(), b"Paper backup");
+        assert!(Key::from_mnemonic(PHRASE, "", &config).unwrap() != key);
+
+        let typo = PHRASE.replace("ankle", "angle");
+        assert!(matches!(
+            Key::from_mnemonic(&typo, "paper", &config),
+            Err(Error::Decode(_))
+        ));
+    }
+}
@@ -5,26 +5,44 @@ This library provides user-friendly utilities for performing AES-256-CBC operati
 
 Currenly supports:
 
-- key derivation with password
+- key derivation with password and a random salt, using PBKDF2, scrypt or Argon2id
 - encryption
-- decryption
+- decryption with a random IV per message
+- AEAD ciphers with optional associated data, for keys whose `algo` is
+  `aes-256-gcm`, `chacha20-poly1305` or `xchacha20-poly1305`
+- HMAC-SHA256 authentication of the whole cyphertext (encrypt-then-MAC)
+- a versioned, self-describing container format (see `format`)
+- streaming encryption and decryption of arbitrarily large files
+- key files protected with a passphrase (see `protect`)
+- encryption for several recipient keys with a random data key (see `recipients`)
+- key material wiped from memory once dropped, see `Key`
+- a local keyring of named keys (see `keyring`)
+- ASCII-armored cyphertexts (see `armor`)
+- the files of `openssl enc -aes-256-cbc` (see `openssl`)
+- keys recreated from a BIP39 mnemonic (see `mnemonic`)
+- keys split into shares, any threshold of which recreate them (see `shamir`)
+- directory trees encrypted file by file with a manifest (see `tree`)
+- files and directories packed into one encrypted archive (see `archive`)
+
+Fallible functions return `Result<_, Error>` rather than panicking.
 
 # Example
 
 This example shows how to create a "standard" printer and execute a search.
 
 ```
-use toolz::aes256cbc::{Key, Config};
+use toolz::aes256cbc::{generate_salt, Key, Config};
 
 let config = Config::from_vec(&[100, 200, 300]);
 
 let password = String::from("I <3 Nickelback");
-let key = Key::from_password(&password.as_bytes(), &config);
+let salt = generate_salt();
+let key = Key::from_password(&password.as_bytes(), &salt, &config).expect("invalid config");
 
 let plaintext = b"Some secret information";
-let cyphertext = key.encrypt(plaintext).ok().expect("encryption failed");
+let cyphertext = key.encrypt(plaintext).expect("encryption failed");
 
-let decrypted = key.decrypt(&cyphertext).ok().expect("decryption failed");
+let decrypted = key.decrypt(&cyphertext).expect("decryption failed");
 
 assert_eq!((*plaintext).to_vec(), decrypted);
 ```
@@ -34,6 +52,7 @@ extern crate crypto;
 extern crate rand;
 
 use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+use crypto::digest::Digest;
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::sha2::Sha256;
@@ -44,12 +63,38 @@ use shellexpand;
 use std::borrow::Borrow;
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+mod aead;
+pub mod archive;
+pub mod armor;
+mod error;
+pub mod format;
+pub mod keyring;
+pub mod mnemonic;
+pub mod openssl;
+pub mod protect;
+pub mod recipients;
+pub mod shamir;
+mod stream;
+pub mod tree;
+pub use self::error::Error;
+use self::format::{Cipher, Envelope, Header};
+pub use self::protect::Protection;
+pub use self::stream::{DecryptingReader, EncryptingWriter};
 
 const ALGO: &'static str = "aes-256-cbc";
 const DIGEST_SIZE: usize = 32;
+///Mixed into the key digest of cyphertexts that carry their own IV
+///and an authentication tag
+const RANDOM_IV_DIGEST_TAG: &[u8] = b"random-iv";
+///Tells the key id of container headers apart from the legacy digest,
+///which must not be disclosed by containers
+const KEY_ID_TAG: &[u8] = b"key-id";
 ///The path used by `Config::default()`
-const DEFAULT_CONFIG_PATH: &'static str = "~/.rustic-toolz.yaml";
+pub const DEFAULT_CONFIG_PATH: &'static str = "~/.rustic-toolz.yaml";
 
 ///The builtin number of cycles for a key derivation
 const KEY_CYCLES: u32 = 1000;
@@ -58,19 +103,96 @@ const SALT_CYCLES: u32 = 1000;
 ///The builtin number of cycles for a ivv derivation
 const IV_CYCLES: u32 = 1000;
 
-const KEY_SIZE: usize = 128;
+///The builtin scrypt cost, as a power of two
+const SCRYPT_LOG_N: u8 = 15;
+///The builtin scrypt block size
+const SCRYPT_R: u32 = 8;
+///The builtin scrypt parallelism
+const SCRYPT_P: u32 = 1;
+
+///The builtin Argon2id memory cost in KiB
+const ARGON2_MEMORY: u32 = 19 * 1024;
+///The builtin Argon2id number of passes
+const ARGON2_TIME: u32 = 2;
+///The builtin Argon2id degree of parallelism
+const ARGON2_PARALLELISM: u32 = 1;
+
+///The size of the key material: cipher key followed by mac key
+const KEY_SIZE: usize = 64;
+///The size of an AES-256 cipher key
+const CIPHER_KEY_SIZE: usize = 32;
 const IV_SIZE: usize = 16;
+///The size of the random salt of password-derived keys
+const SALT_SIZE: usize = 32;
+///The number of bytes shown by `Key::fingerprint`
+const FINGERPRINT_SIZE: usize = 8;
 const BUF_SIZE: usize = 4096;
 
 /// Reads the given filename as Vec<u8>
-pub fn read_bytes(filename: &str) -> Vec<u8> {
-    let f = File::open(filename).expect("failed to open file");
+pub fn read_bytes(filename: &str) -> Result<Vec<u8>, Error> {
+    let f = File::open(filename)?;
     let mut reader = BufReader::new(f);
     let mut buffer = Vec::new();
-    reader
-        .read_to_end(&mut buffer)
-        .expect("failed to read file");
-    buffer
+    reader.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+/// Writes the new content of `filename` through `write` into a temporary
+/// file next to it, which replaces `filename` once synced to disk
+///
+/// `filename` is left untouched if anything fails.
+pub fn replace_file<F>(filename: &str, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut File) -> Result<(), Error>,
+{
+    let path = Path::new(filename);
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::decode(format!("not a file name: {}", filename)))?;
+    let temporary = path.with_file_name(format!(
+        ".{}.{:016x}.tmp",
+        name.to_string_lossy(),
+        rand::thread_rng().next_u64()
+    ));
+    let result: Result<(), Error> = (|| {
+        let mut file = File::create(&temporary)?;
+        write(&mut file)?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temporary, metadata.permissions())?;
+        }
+        fs::rename(&temporary, path)?;
+        // the rename itself is only durable once the directory is synced
+        #[cfg(unix)]
+        File::open(match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        })?
+        .sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+/// Overwrites the whole content of `file` with random bytes, synced to disk
+///
+/// Journaling or copy-on-write filesystems and SSDs may still keep copies
+/// of the old content elsewhere: this makes it harder to recover, not
+/// impossible.
+pub fn shred(file: &mut File) -> Result<(), Error> {
+    let mut remaining = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut rng = rand::thread_rng();
+    let mut buffer = vec![0u8; 16 * BUF_SIZE];
+    while remaining > 0 {
+        let size = remaining.min(buffer.len() as u64) as usize;
+        rng.fill_bytes(&mut buffer[..size]);
+        file.write_all(&buffer[..size])?;
+        remaining -= size as u64;
+    }
+    file.sync_all()?;
+    Ok(())
 }
 pub fn bytes_match(a: &[u8], b: &[u8]) -> bool {
     let diff = a
@@ -80,10 +202,25 @@ pub fn bytes_match(a: &[u8], b: &[u8]) -> bool {
         .fold(0, |acc, x| acc | x);
     diff == 0 && a.len() == b.len()
 }
-/// Dummy example of hmac_256_digest
-pub fn hmac_256_digest(mac_key: &[u8], iv: &[u8]) -> [u8; DIGEST_SIZE] {
+/// Reads the envelope at the start of a file, `None` if it is too short
+/// or malformed to hold one, or not valid armor
+pub(crate) fn read_envelope(filename: &str) -> Result<Option<Envelope>, Error> {
+    let mut reader = match armor::open_file(filename) {
+        Ok(reader) => reader,
+        Err(Error::Decode(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match Envelope::read_from(&mut reader) {
+        Ok(envelope) => Ok(Some(envelope)),
+        Err(Error::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(Error::Decode(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+/// Computes the HMAC-SHA256 of `data` with the given mac key
+pub fn hmac_256_digest(mac_key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
     let mut mac = Hmac::new(Sha256::new(), &mac_key);
-    mac.input(&iv);
+    mac.input(&data);
     let result = mac.result();
     let mac_digest = result.code();
     mac_digest[..DIGEST_SIZE].try_into().unwrap()
@@ -108,20 +245,31 @@ pub fn b64encode(bytes: &[u8]) -> String {
 ///
 /// ```
 /// use toolz::aes256cbc::b64decode;
-/// assert_eq!(b"Hello".to_vec(), b64decode(b"SGVsbG8="));
+/// assert_eq!(b"Hello".to_vec(), b64decode(b"SGVsbG8=").unwrap());
 /// ```
-pub fn b64decode(bytes: &[u8]) -> Vec<u8> {
-    let bytes = base64::decode(&bytes).unwrap();
-    bytes
+pub fn b64decode(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let bytes = base64::decode(&bytes)?;
+    Ok(bytes)
 }
 
 /// Generates a random KEY;
-pub fn generate_key() -> [u8; KEY_SIZE] {
+pub fn generate_key() -> Zeroizing<[u8; KEY_SIZE]> {
     let mut rng = rand::thread_rng();
-    let mut key: [u8; KEY_SIZE] = [0; KEY_SIZE];
-    rng.fill_bytes(&mut key);
+    let mut key = Zeroizing::new([0; KEY_SIZE]);
+    rng.fill_bytes(&mut *key);
     key
 }
+/// Generates a random salt for `Key::from_password`;
+pub fn generate_salt() -> [u8; SALT_SIZE] {
+    let mut rng = rand::thread_rng();
+    let mut salt: [u8; SALT_SIZE] = [0; SALT_SIZE];
+    rng.fill_bytes(&mut salt);
+    salt
+}
+/// The current UTC time, as recorded in the `created` field of keys
+fn timestamp() -> String {
+    time::OffsetDateTime::now_utc().format("%Y-%m-%dT%H:%M:%SZ")
+}
 /// Generates a random IV;
 pub fn generate_iv() -> [u8; IV_SIZE] {
     let mut rng = rand::thread_rng();
@@ -153,25 +301,83 @@ impl CyclesConfig {
     }
 }
 
+/// The scrypt parameters: a cost of `2^log_n`, the block size `r`
+/// and the parallelism `p`
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct ScryptConfig {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+impl ScryptConfig {
+    /// The builtin scrypt parameters
+    pub fn builtin() -> ScryptConfig {
+        ScryptConfig {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+}
+
+/// The Argon2id parameters: the `memory` cost in KiB, the `time` cost
+/// in passes and the `parallelism` in lanes
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Argon2Config {
+    pub memory: u32,
+    pub time: u32,
+    pub parallelism: u32,
+}
+impl Argon2Config {
+    /// The builtin Argon2id parameters
+    pub fn builtin() -> Argon2Config {
+        Argon2Config {
+            memory: ARGON2_MEMORY,
+            time: ARGON2_TIME,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
+}
+
+/// The key derivation function of `Config::derive_key`
+///
+/// In YAML either `pbkdf2`, `scrypt: {log_n: 15, r: 8, p: 1}`
+/// or `argon2id: {memory: 19456, time: 2, parallelism: 1}`.
+#[derive(PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfConfig {
+    /// PBKDF2-HMAC-SHA256 with `cycles.key` iterations
+    #[default]
+    Pbkdf2,
+    /// scrypt with the given parameters
+    Scrypt(ScryptConfig),
+    /// Argon2id (version 0x13) with the given parameters
+    Argon2id(Argon2Config),
+}
+
 /// The configuration for the Key.
 ///
-/// It contains the cycles for key, salt and iv used in key derivation.
-#[derive(PartialEq, Serialize, Deserialize)]
+/// It contains the cycles for key, salt and iv used in key derivation
+/// and the key derivation function itself, PBKDF2 unless configured.
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub cycles: CyclesConfig,
+    #[serde(default)]
+    pub kdf: KdfConfig,
     pub default_key_path: Option<String>,
 }
 
 impl Config {
     /// Creates a new config based on a YAML-serialized string
-    pub fn from_yaml(data: String) -> Config {
-        let key: Config = serde_yaml::from_str(&data).expect("failed to deserialized yaml key");
-        key
+    pub fn from_yaml(data: String) -> Result<Config, Error> {
+        let config: Config = serde_yaml::from_str(&data)?;
+        Ok(config)
     }
     /// Creates a new config based on a &Vec<u32>
     pub fn from_vec(vec: &[u32; 3]) -> Config {
         Config {
             cycles: CyclesConfig::from_vec(vec),
+            kdf: KdfConfig::Pbkdf2,
             default_key_path: None,
         }
     }
@@ -184,30 +390,45 @@ impl Config {
                 salt: SALT_CYCLES,
                 iv: IV_CYCLES,
             },
+            kdf: KdfConfig::Pbkdf2,
+        }
+    }
+    /// Uses the given key derivation function
+    pub fn with_kdf(self, kdf: KdfConfig) -> Config {
+        Config { kdf, ..self }
+    }
+    /// Uses the given key file when no key is specified
+    pub fn with_default_key_path(self, default_key_path: Option<String>) -> Config {
+        Config {
+            default_key_path,
+            ..self
         }
     }
 
     /// Exports config to a yaml string
-    pub fn to_yaml(&self) -> String {
-        match serde_yaml::to_string(&self) {
-            Ok(val) => val,
-            Err(e) => {
-                eprintln!("failed to encode key to yaml: {}", e);
-                String::new()
-            }
-        }
+    pub fn to_yaml(&self) -> Result<String, Error> {
+        Ok(serde_yaml::to_string(&self)?)
     }
 
     /// Loads the default config from `DEFAULT_CONFIG_PATH`
-    pub fn default() -> Option<Config> {
+    pub fn default() -> Result<Config, Error> {
         let filename = shellexpand::tilde(DEFAULT_CONFIG_PATH);
         Config::import(filename.borrow())
     }
-    /// Loads the default config from a yaml file
-    pub fn import(filename: &str) -> Option<Config> {
+    /// Stores the config in `DEFAULT_CONFIG_PATH`, where `Config::default`
+    /// loads it from
+    pub fn save(&self) -> Result<String, Error> {
+        let filename = shellexpand::tilde(DEFAULT_CONFIG_PATH).to_string();
+        fs::write(&filename, self.to_yaml()?)?;
+        Ok(filename)
+    }
+    /// Loads the config from a yaml file, falling back to the
+    /// builtin config when the file does not exist
+    pub fn import(filename: &str) -> Result<Config, Error> {
         match fs::read_to_string(filename) {
-            Ok(yaml) => Some(Config::from_yaml(yaml)),
-            Err(_) => Some(Config::builtin(None)),
+            Ok(yaml) => Config::from_yaml(yaml),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::builtin(None)),
+            Err(e) => Err(Error::Io(e)),
         }
     }
     pub fn iv_cycles(&self) -> u32 {
@@ -219,11 +440,37 @@ impl Config {
     pub fn salt_cycles(&self) -> u32 {
         self.cycles.salt
     }
-    pub fn derive_key<'a>(&self, password: &[u8], salt: &[u8]) -> [u8; KEY_SIZE] {
-        let mut dk = [0u8; KEY_SIZE]; // derived key
-        let mut mac = Hmac::new(Sha256::new(), password);
-        pbkdf2::pbkdf2(&mut mac, &salt, self.key_cycles(), &mut dk);
-        dk
+    /// Derives the key material, which is wiped from memory once dropped
+    pub fn derive_key<'a>(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; KEY_SIZE]>, Error> {
+        let mut dk = Zeroizing::new([0u8; KEY_SIZE]); // derived key
+        match self.kdf {
+            KdfConfig::Pbkdf2 => {
+                let mut mac = Hmac::new(Sha256::new(), password);
+                pbkdf2::pbkdf2(&mut mac, &salt, self.key_cycles(), &mut *dk);
+            }
+            KdfConfig::Scrypt(params) => {
+                let params = scrypt::ScryptParams::new(params.log_n, params.r, params.p)
+                    .map_err(Error::kdf)?;
+                scrypt::scrypt(password, salt, &params, &mut *dk).map_err(Error::kdf)?;
+            }
+            KdfConfig::Argon2id(params) => {
+                let params = argon2::Params::new(
+                    params.memory,
+                    params.time,
+                    params.parallelism,
+                    Some(KEY_SIZE),
+                )
+                .map_err(Error::kdf)?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password, salt, &mut *dk)
+                    .map_err(Error::kdf)?;
+            }
+        }
+        Ok(dk)
     }
     pub fn derive_salt<'a>(&self, password: &[u8]) -> [u8; KEY_SIZE] {
         let mut dk = [0u8; KEY_SIZE]; // derived key
@@ -239,44 +486,146 @@ impl Config {
     }
 }
 /// AES-256 Key data
-#[derive(PartialEq, Serialize, Deserialize)]
+///
+/// The `key` and `mac` secrets are wiped from memory when the key is
+/// dropped. `Key` does not implement `Debug` nor `Serialize`: `to_yaml`
+/// and `export` are the only ways to write them out.
+#[derive(Clone, Deserialize)]
 pub struct Key {
     pub algo: String,
+    /// The base64 cipher key, empty when `protected`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub key: String,
+    /// The base64 mac key, empty when `protected`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub mac: String,
     pub iv: String,
     pub magic: Option<Vec<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfConfig>,
+    /// The base64 random salt of password-derived keys, `None` for
+    /// random keys and legacy keys whose salt is derived from the password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+    /// The key material wrapped with a passphrase, see `Key::protect`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected: Option<Protection>,
+    /// When the key was generated, `None` for keys older than this field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+}
+/// Keys are equal when they hold the same material and parameters,
+/// whenever they were created
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.algo == other.algo
+            && bytes_match(self.key.as_bytes(), other.key.as_bytes())
+            && bytes_match(self.mac.as_bytes(), other.mac.as_bytes())
+            && self.iv == other.iv
+            && self.magic == other.magic
+            && self.kdf == other.kdf
+            && self.salt == other.salt
+            && self.protected == other.protected
+    }
+}
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.key.zeroize();
+        self.mac.zeroize();
+    }
+}
+/// The fields of a key file, borrowed from the `Key` being exported
+#[derive(Serialize)]
+struct KeyFile<'a> {
+    algo: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    key: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    mac: &'a str,
+    iv: &'a str,
+    magic: Option<&'a Vec<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kdf: Option<&'a KdfConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    salt: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protected: Option<&'a Protection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<&'a str>,
+}
+impl<'a> From<&'a Key> for KeyFile<'a> {
+    fn from(key: &'a Key) -> KeyFile<'a> {
+        KeyFile {
+            algo: &key.algo,
+            key: &key.key,
+            mac: &key.mac,
+            iv: &key.iv,
+            magic: key.magic.as_ref(),
+            kdf: key.kdf.as_ref(),
+            salt: key.salt.as_deref(),
+            protected: key.protected.as_ref(),
+            created: key.created.as_deref(),
+        }
+    }
 }
 impl Key {
     /// Load a key from a yaml string
-    pub fn from_yaml(data: String) -> Key {
-        let key: Key = serde_yaml::from_str(&data).expect("failed to deserialized yaml key");
-        key
+    pub fn from_yaml(data: String) -> Result<Key, Error> {
+        let key: Key = serde_yaml::from_str(&data)?;
+        Ok(key)
+    }
+    /// Derive a key from a password and a salt, usually from `generate_salt()`,
+    /// using the cycles and key derivation function from the given config
+    pub fn from_password(password: &[u8], salt: &[u8], config: &Config) -> Result<Key, Error> {
+        let key_material = config.derive_key(password, salt)?;
+
+        let enc_key = &key_material[..CIPHER_KEY_SIZE];
+        let mac_key = &key_material[CIPHER_KEY_SIZE..];
+        let iv = &hmac_256_digest(mac_key, salt)[..IV_SIZE];
+
+        Ok(Key {
+            key: b64encode(enc_key),
+            mac: b64encode(mac_key),
+            iv: b64encode(&iv),
+            algo: String::from(ALGO),
+            magic: Some(config.cycles.to_vec()),
+            kdf: Some(config.kdf),
+            salt: Some(b64encode(salt)),
+            protected: None,
+            created: Some(timestamp()),
+        })
     }
-    /// Derive a key from a password using the cycles from the given config
-    pub fn from_password(password: &[u8], config: &Config) -> Key {
+    /// Derive a key from a password alone, with the salt and iv derived
+    /// from the password as keys were before `from_password` took a salt
+    ///
+    /// The same password always gives the same key, so this is only
+    /// meant to decrypt data encrypted with such keys.
+    pub fn from_legacy_password(password: &[u8], config: &Config) -> Result<Key, Error> {
         let iv = config.derive_iv(password);
         let salt = config.derive_salt(password);
-        //let salt = generate_iv();
-        let key_material = config.derive_key(password, &salt);
+        let key_material = config.derive_key(password, &salt)?;
 
-        let enc_key = &key_material[0..127];
-        let mac_key = &key_material[128..255];
+        let enc_key = &key_material[..CIPHER_KEY_SIZE];
+        let mac_key = &key_material[CIPHER_KEY_SIZE..];
 
-        Key {
+        Ok(Key {
             key: b64encode(&enc_key),
             mac: b64encode(&mac_key),
             iv: b64encode(&iv),
             algo: String::from(ALGO),
             magic: Some(config.cycles.to_vec()),
-        }
+            kdf: Some(config.kdf),
+            salt: None,
+            protected: None,
+            created: None,
+        })
     }
     /// Generate a new key
     pub fn generate() -> Key {
         let iv = generate_iv();
         let key_material = generate_key();
-        let enc_key = &key_material[0..127];
-        let mac_key = &key_material[128..255];
+        let enc_key = &key_material[..CIPHER_KEY_SIZE];
+        let mac_key = &key_material[CIPHER_KEY_SIZE..];
 
         Key {
             key: b64encode(&enc_key),
@@ -284,73 +633,199 @@ impl Key {
             iv: b64encode(&iv),
             algo: String::from(ALGO),
             magic: None,
+            kdf: None,
+            salt: None,
+            protected: None,
+            created: Some(timestamp()),
         }
     }
+    /// The cipher named by the `algo` field
+    pub fn cipher(&self) -> Result<Cipher, Error> {
+        Cipher::from_name(&self.algo)
+            .ok_or_else(|| Error::decode(format!("unsupported algo: {}", self.algo)))
+    }
+    /// Uses the given cipher for this key
+    pub fn with_cipher(mut self, cipher: Cipher) -> Key {
+        self.algo = String::from(cipher.name());
+        self
+    }
     /// Checks if a file is encrypted with this key
-    pub fn owns_file(&self, filename: &str) -> bool {
-        let mut fd =
-            File::open(filename).expect(format!("failed to open file {}", filename).as_str());
-        let mut buffer = [0; DIGEST_SIZE];
-        fd.read(&mut buffer)
-            .expect(format!("failed to read the first bytes of {}", filename).as_str());
-
-        self.check_digest(&buffer)
+    ///
+    /// Files too short to hold a header are not owned by any key.
+    pub fn owns_file(&self, filename: &str) -> Result<bool, Error> {
+        match read_envelope(filename)? {
+            Some(envelope) => self.owns_envelope(&envelope),
+            None => Ok(false),
+        }
+    }
+    /// Checks if a file encrypted with this key was not tampered with
+    ///
+    /// Fails with `Error::Unauthenticated` on legacy cyphertexts using the
+    /// key's own IV, which carry no authentication tag.
+    pub fn verify_file(&self, filename: &str) -> Result<bool, Error> {
+        self.verify(armor::open_file(filename)?)
+    }
+    /// Re-encrypts a file owned by this key with `new_key`, replacing it
+    /// only once the new cyphertext is fully written
+    ///
+    /// Fails with `Error::WrongKey` if the file is not owned by this key and
+    /// with `Error::Integrity` if it was tampered with, leaving it untouched.
+    /// Armored files are rewritten in binary.
+    pub fn rotate_file(&self, new_key: &Key, filename: &str) -> Result<(), Error> {
+        if !self.owns_file(filename)? {
+            return Err(Error::WrongKey);
+        }
+        let mut reader = DecryptingReader::new(self, armor::open_file(filename)?)?;
+        replace_file(filename, |file| {
+            let mut writer = EncryptingWriter::new(new_key, BufWriter::new(file))?;
+            std::io::copy(&mut reader, &mut writer)?;
+            writer.finish()?;
+            Ok(())
+        })
     }
     /// Checks the digest of the given bytes
-    pub fn check_digest(&self, buffer: &[u8; DIGEST_SIZE]) -> bool {
-        let digest = self.digest();
-        bytes_match(buffer, &digest)
+    ///
+    /// Both the digest of authenticated cyphertexts with a random IV and
+    /// the legacy digest of cyphertexts using the key's own IV are accepted.
+    pub fn check_digest(&self, buffer: &[u8; DIGEST_SIZE]) -> Result<bool, Error> {
+        let random_iv_digest = self.random_iv_digest()?;
+        let digest = self.digest()?;
+        Ok(bytes_match(buffer, &random_iv_digest) | bytes_match(buffer, &digest))
+    }
+    /// Whether the given digest precedes a per-message IV in the cyphertext
+    /// and is covered by a trailing authentication tag
+    pub fn has_random_iv(&self, buffer: &[u8; DIGEST_SIZE]) -> Result<bool, Error> {
+        Ok(bytes_match(buffer, &self.random_iv_digest()?))
     }
     /// Load key from a YAML file
-    pub fn import(filename: &str) -> Key {
-        let yaml = fs::read_to_string(filename).expect("cannot read key file");
-        Key::from_yaml(yaml)
+    ///
+    /// The passphrase of protected keys is asked on the terminal.
+    pub fn import(filename: &str) -> Result<Key, Error> {
+        let yaml = fs::read_to_string(filename)?;
+        let key = Key::from_yaml(yaml)?;
+        if key.is_protected() {
+            let prompt = format!("Passphrase for {}: ", filename);
+            let passphrase = Zeroizing::new(rpassword::read_password_from_tty(Some(&prompt))?);
+            return key.unprotect(passphrase.as_bytes());
+        }
+        Ok(key)
+    }
+    /// A short and stable identifier of the key, made of the first bytes
+    /// of the SHA-256 of its `digest()`, in groups of four hex digits
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use toolz::aes256cbc::Key;
+    /// let fingerprint = Key::generate().fingerprint().unwrap();
+    /// assert_eq!(fingerprint.len(), "0000:0000:0000:0000".len());
+    /// ```
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        let mut sha = Sha256::new();
+        sha.input(&self.digest()?);
+        let mut hash = [0; DIGEST_SIZE];
+        sha.result(&mut hash);
+        let groups: Vec<String> = hash[..FINGERPRINT_SIZE]
+            .chunks(2)
+            .map(|group| format!("{:02x}{:02x}", group[0], group[1]))
+            .collect();
+        Ok(groups.join(":"))
     }
-    pub fn digest(&self) -> [u8; DIGEST_SIZE] {
-        let mac = self.mac_bytes();
-        let iv = self.iv_bytes();
-        hmac_256_digest(&mac, &iv)
+    pub fn digest(&self) -> Result<[u8; DIGEST_SIZE], Error> {
+        let mac = self.mac_bytes()?;
+        let iv = self.iv_bytes()?;
+        Ok(hmac_256_digest(&mac, &iv))
     }
-    pub fn iv_bytes(&self) -> Vec<u8> {
+    /// The id of the key in container headers and recipient slots
+    ///
+    /// It differs from `digest()`, which starts the legacy cyphertexts
+    /// without an authentication tag, so that it cannot be used to forge one.
+    pub fn key_id(&self) -> Result<[u8; DIGEST_SIZE], Error> {
+        let mac = self.mac_bytes()?;
+        let iv = self.iv_bytes()?;
+        Ok(hmac_256_digest(&mac, &[KEY_ID_TAG, &iv].concat()))
+    }
+    /// The digest written before cyphertexts that carry their own IV
+    /// and authentication tag
+    pub fn random_iv_digest(&self) -> Result<[u8; DIGEST_SIZE], Error> {
+        let mac = self.mac_bytes()?;
+        let iv = self.iv_bytes()?;
+        Ok(hmac_256_digest(&mac, &[RANDOM_IV_DIGEST_TAG, &iv].concat()))
+    }
+    pub fn iv_bytes(&self) -> Result<Vec<u8>, Error> {
         b64decode(self.iv.as_bytes())
     }
-    pub fn key_bytes(&self) -> Vec<u8> {
-        b64decode(self.key.as_bytes())
+    /// The cipher key, wiped from memory once dropped
+    pub fn key_bytes(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        Ok(Zeroizing::new(b64decode(self.key.as_bytes())?))
     }
-    pub fn mac_bytes(&self) -> Vec<u8> {
-        b64decode(self.mac.as_bytes())
+    /// The mac key, wiped from memory once dropped
+    pub fn mac_bytes(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        Ok(Zeroizing::new(b64decode(self.mac.as_bytes())?))
     }
-    /// Serialize key into a YAML string
-    pub fn to_yaml(&self) -> String {
-        match serde_yaml::to_string(&self) {
-            Ok(val) => val,
-            Err(e) => {
-                eprintln!("failed to encode key to yaml: {}", e);
-                String::new()
-            }
+    /// The random salt of the key, empty when there is none
+    pub fn salt_bytes(&self) -> Result<Vec<u8>, Error> {
+        match self.salt {
+            Some(ref salt) => b64decode(salt.as_bytes()),
+            None => Ok(Vec::new()),
+        }
+    }
+    /// The cipher key, checked to be usable with AES-256
+    fn cipher_key(&self, iv: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let key = self.key_bytes()?;
+        if key.len() != CIPHER_KEY_SIZE {
+            return Err(Error::decode("invalid cipher key size"));
+        }
+        if iv.len() != IV_SIZE {
+            return Err(Error::decode("invalid IV size"));
         }
+        Ok(key)
+    }
+    /// Serialize key into a YAML string
+    pub fn to_yaml(&self) -> Result<String, Error> {
+        Ok(serde_yaml::to_string(&KeyFile::from(self))?)
     }
     /// Store YAML-serialized key into a file
-    pub fn export(&self, filename: &str) -> String {
-        let yaml = self.to_yaml();
-        let mut file = File::create(filename).expect("failed to create new file");
-        file.write(yaml.as_ref()).unwrap();
-        String::from(filename)
+    pub fn export(&self, filename: &str) -> Result<String, Error> {
+        let yaml = self.to_yaml()?;
+        let mut file = File::create(filename)?;
+        file.write_all(yaml.as_ref())?;
+        Ok(String::from(filename))
     }
 
-    /// Encrypt a buffer with the key
-    /// AES-256/CBC/Pkcs encryption.
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
-        // Create an encryptor instance of the best performing
-        // type available for the platform.
-        let enc_key = self.key_bytes();
-        let iv = self.iv_bytes();
-        let mut encryptor = aes::cbc_encryptor(
+    /// Create an encryptor instance of the best performing
+    /// type available for the platform.
+    pub fn encryptor(&self, iv: &[u8]) -> Result<Box<dyn symmetriccipher::Encryptor>, Error> {
+        Ok(aes::cbc_encryptor(
             aes::KeySize::KeySize256,
-            &enc_key,
-            &iv,
+            &self.cipher_key(iv)?,
+            iv,
             blockmodes::PkcsPadding,
-        );
+        ))
+    }
+    /// Create a decryptor instance of the best performing
+    /// type available for the platform.
+    pub fn decryptor(&self, iv: &[u8]) -> Result<Box<dyn symmetriccipher::Decryptor>, Error> {
+        Ok(aes::cbc_decryptor(
+            aes::KeySize::KeySize256,
+            &self.cipher_key(iv)?,
+            iv,
+            blockmodes::PkcsPadding,
+        ))
+    }
+
+    /// Encrypt a buffer with the key
+    /// AES-256/CBC/Pkcs encryption with a random IV in a container
+    /// (see `format`), followed by the HMAC-SHA256 of everything before it,
+    /// or the AEAD cipher of the key.
+    ///
+    /// See `EncryptingWriter` for encrypting data that does not fit in memory.
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.cipher()?.is_aead() {
+            return self.encrypt_with_aad(data, &[]);
+        }
+        let iv = generate_iv();
+        let mut encryptor = self.encryptor(&iv)?;
 
         // Each encryption operation encrypts some data from
         // an input buffer into an output buffer. Those buffers
@@ -362,9 +837,8 @@ impl Key {
         let mut buffer = [0; BUF_SIZE];
         let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
 
-        // The first 32 bytes of the cyphertext are the digest
-        let digest = self.digest();
-        cyphertext.extend_from_slice(&digest);
+        // The cyphertext starts with the container header
+        cyphertext.extend_from_slice(&Header::new(self, &iv)?.to_bytes());
 
         // Each encryption operation will "make progress". "Making progress"
         // is a bit loosely defined, but basically, at the end of each operation
@@ -406,30 +880,62 @@ impl Key {
                 BufferResult::BufferOverflow => {}
             }
         }
+
+        // The last 32 bytes authenticate the header and encrypted data
+        let tag = hmac_256_digest(&self.mac_bytes()?, &cyphertext);
+        cyphertext.extend_from_slice(&tag);
         Ok(cyphertext)
     }
 
-    /// Decrypts a buffer with the key
-    /// AES-256/CBC/Pkcs decryption.
-    pub fn decrypt(
-        &self,
-        cyphertext: &[u8],
-    ) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
-        let mut decryptor = aes::cbc_decryptor(
-            aes::KeySize::KeySize256,
-            &self.key_bytes(),
-            &self.iv_bytes(),
-            blockmodes::PkcsPadding,
-        );
+    /// Encrypt a buffer with the key, authenticating the associated
+    /// data `aad` along with it without storing it in the cyphertext
+    ///
+    /// Only AEAD ciphers support associated data, other keys fail with
+    /// `Error::Unsupported` unless it is empty.
+    pub fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut writer = EncryptingWriter::with_aad(self, aad, Vec::new())?;
+        writer.write_all(data)?;
+        writer.finish()
+    }
 
+    /// Decrypts a buffer encrypted by `encrypt_with_aad` with the same
+    /// associated data, failing with `Error::Integrity` otherwise
+    pub fn decrypt_with_aad(&self, cyphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut reader = DecryptingReader::with_aad(self, aad, cyphertext)?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// Decrypts a buffer with the key
+    /// AES-256/CBC/Pkcs or AEAD decryption, according to its header.
+    ///
+    /// Fails with `Error::Unauthenticated` on legacy cyphertexts without
+    /// an authentication tag, which only
+    /// `DecryptingReader::with_unauthenticated_legacy` decrypts.
+    /// See `DecryptingReader` for decrypting data that does not fit in memory.
+    pub fn decrypt(&self, cyphertext: &[u8]) -> Result<Vec<u8>, Error> {
         let mut plaintext = Vec::<u8>::new();
-        let hmac_bytes: [u8; DIGEST_SIZE] = cyphertext[..DIGEST_SIZE].try_into().unwrap();
-        if !self.check_digest(&hmac_bytes) {
-            eprintln!("Cannot decrypt: data was not encrypted by this key");
-            return Ok((*cyphertext).to_vec());
+        let mut remaining = cyphertext;
+        let envelope = Envelope::read_from(&mut remaining)?;
+        let opened = self.open(&envelope, &mut remaining, false)?;
+        if opened.cipher.is_aead() {
+            return self.decrypt_with_aad(cyphertext, &[]);
         }
 
-        let cyphertext = &cyphertext[DIGEST_SIZE..];
+        // The tag is checked before anything is decrypted.
+        if remaining.len() < DIGEST_SIZE {
+            return Err(Error::Integrity);
+        }
+        let (authenticated, tag) = cyphertext.split_at(cyphertext.len() - DIGEST_SIZE);
+        if !bytes_match(
+            tag,
+            &hmac_256_digest(&opened.key.mac_bytes()?, authenticated),
+        ) {
+            return Err(Error::Integrity);
+        }
+        let cyphertext = &remaining[..remaining.len() - DIGEST_SIZE];
+        let mut decryptor = opened.key.decryptor(&opened.iv)?;
         let mut read_buffer = buffer::RefReadBuffer::new(&cyphertext);
         let mut buffer = [0; BUF_SIZE];
         let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
@@ -455,15 +961,24 @@ impl Key {
 }
 #[cfg(test)]
 mod tests {
+    use crate::aes256cbc::format::Envelope;
     use crate::aes256cbc::Config;
     use crate::aes256cbc::Key;
+    use crate::aes256cbc::{
+        b64decode, generate_salt, replace_file, shred, Argon2Config, DecryptingReader, Error,
+        KdfConfig, ScryptConfig,
+    };
+    use crypto::buffer::{ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
     use k9::assert_equal;
+    use std::io::Read;
+
+    const SALT: &[u8] = b"sodium chloride";
 
     #[test]
     fn test_encrypt_and_decrypt() {
         let config = Config::builtin(None);
         let password = String::from("123456");
-        let key = Key::from_password(&password.as_bytes(), &config);
+        let key = Key::from_password(&password.as_bytes(), &generate_salt(), &config).unwrap();
 
         let plaintext = b"This is a secret";
         let cyphertext = key.encrypt(plaintext).unwrap();
@@ -471,4 +986,244 @@ mod tests {
         let decrypted = key.decrypt(&cyphertext).unwrap();
         assert_equal!(decrypted, b"This is a secret");
     }
+
+    #[test]
+    fn test_scrypt_key_derivation() {
+        let config = Config::builtin(None).with_kdf(KdfConfig::Scrypt(ScryptConfig {
+            log_n: 4,
+            r: 8,
+            p: 1,
+        }));
+        let key = Key::from_password(b"123456", SALT, &config).unwrap();
+        let pbkdf2_key = Key::from_password(b"123456", SALT, &Config::builtin(None)).unwrap();
+        assert_ne!(key.key, pbkdf2_key.key);
+
+        let imported = Key::from_yaml(key.to_yaml().unwrap()).unwrap();
+        assert!(imported.kdf == Some(config.kdf));
+
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+        assert_equal!(imported.decrypt(&cyphertext).unwrap(), b"This is a secret");
+    }
+
+    #[test]
+    fn test_argon2id_key_derivation() {
+        let argon2id = KdfConfig::Argon2id(Argon2Config {
+            memory: 64,
+            time: 1,
+            parallelism: 1,
+        });
+        let key =
+            Key::from_password(b"123456", SALT, &Config::builtin(None).with_kdf(argon2id)).unwrap();
+        let pbkdf2_key = Key::from_password(b"123456", SALT, &Config::builtin(None)).unwrap();
+        assert_ne!(key.key, pbkdf2_key.key);
+
+        let imported = Key::from_yaml(key.to_yaml().unwrap()).unwrap();
+        assert!(imported.kdf == Some(argon2id));
+
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+        assert_equal!(imported.decrypt(&cyphertext).unwrap(), b"This is a secret");
+
+        let invalid = KdfConfig::Argon2id(Argon2Config {
+            memory: 1,
+            time: 1,
+            parallelism: 1,
+        });
+        let result = Key::from_password(b"123456", SALT, &Config::builtin(None).with_kdf(invalid));
+        assert!(matches!(result, Err(Error::Kdf(_))));
+    }
+
+    #[test]
+    fn test_config_kdf_from_yaml() {
+        let yaml = "cycles: {key: 1, salt: 2, iv: 3}\nkdf:\n  scrypt: {log_n: 4, r: 8, p: 1}\n";
+        let config = Config::from_yaml(String::from(yaml)).unwrap();
+        assert!(
+            config.kdf
+                == KdfConfig::Scrypt(ScryptConfig {
+                    log_n: 4,
+                    r: 8,
+                    p: 1
+                })
+        );
+
+        let yaml = "cycles: {key: 1, salt: 2, iv: 3}\nkdf:\n  argon2id: {memory: 64, time: 1, parallelism: 1}\n";
+        let config = Config::from_yaml(String::from(yaml)).unwrap();
+        assert!(
+            config.kdf
+                == KdfConfig::Argon2id(Argon2Config {
+                    memory: 64,
+                    time: 1,
+                    parallelism: 1
+                })
+        );
+
+        let legacy = Config::from_yaml(String::from("cycles: {key: 1, salt: 2, iv: 3}")).unwrap();
+        assert!(legacy.kdf == KdfConfig::Pbkdf2);
+    }
+
+    #[test]
+    fn test_password_keys_depend_on_the_salt() {
+        let config = Config::builtin(None);
+        let key = Key::from_password(b"123456", SALT, &config).unwrap();
+        let other = Key::from_password(b"123456", &generate_salt(), &config).unwrap();
+        assert_ne!(key.key, other.key);
+        assert_ne!(key.iv, other.iv);
+
+        let imported = Key::from_yaml(key.to_yaml().unwrap()).unwrap();
+        assert_equal!(imported.salt_bytes().unwrap(), SALT);
+        let derived = Key::from_password(b"123456", &imported.salt_bytes().unwrap(), &config);
+        assert!(derived.unwrap() == key);
+
+        let legacy = Key::from_legacy_password(b"123456", &config).unwrap();
+        assert!(legacy.salt.is_none());
+        assert!(Key::from_legacy_password(b"123456", &config).unwrap() == legacy);
+    }
+
+    #[test]
+    fn test_encrypt_uses_a_random_iv_per_message() {
+        let key = Key::generate();
+
+        let first = key.encrypt(b"This is a secret").unwrap();
+        let second = key.encrypt(b"This is a secret").unwrap();
+
+        let (first_header, second_header) = match (
+            Envelope::read_from(&mut first.as_slice()).unwrap(),
+            Envelope::read_from(&mut second.as_slice()).unwrap(),
+        ) {
+            (Envelope::Container(first), Envelope::Container(second)) => (first, second),
+            _ => panic!("expected containers"),
+        };
+        assert_equal!(first_header.key_id, second_header.key_id);
+        assert_ne!(first_header.iv, second_header.iv);
+        assert_equal!(key.decrypt(&second).unwrap(), b"This is a secret");
+    }
+
+    #[test]
+    fn test_decrypt_legacy_fixed_iv_cyphertext() {
+        let key = Key::generate();
+        let mut cyphertext = key.digest().unwrap().to_vec();
+        let mut encryptor = key.encryptor(&key.iv_bytes().unwrap()).unwrap();
+        let mut buffer = [0; 64];
+        let mut read_buffer = RefReadBuffer::new(b"This is a secret");
+        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+        encryptor
+            .encrypt(&mut read_buffer, &mut write_buffer, true)
+            .unwrap();
+        cyphertext.extend_from_slice(write_buffer.take_read_buffer().take_remaining());
+
+        assert!(matches!(
+            key.decrypt(&cyphertext),
+            Err(Error::Unauthenticated)
+        ));
+        assert!(matches!(
+            key.verify(cyphertext.as_slice()),
+            Err(Error::Unauthenticated)
+        ));
+        let mut reader =
+            DecryptingReader::with_unauthenticated_legacy(&key, cyphertext.as_slice()).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_equal!(decrypted, b"This is a secret");
+    }
+
+    #[test]
+    fn test_container_does_not_disclose_the_legacy_digest() {
+        let key = Key::generate();
+        let cyphertext = key.encrypt(&[7; 64]).unwrap();
+        let header = match Envelope::read_from(&mut cyphertext.as_slice()).unwrap() {
+            Envelope::Container(header) => header,
+            Envelope::Digest(_) => panic!("expected a container"),
+        };
+        assert_ne!(header.key_id, key.digest().unwrap());
+
+        // a legacy layout forged from the key id and blocks of the container
+        let body = &cyphertext[header.to_bytes().len()..cyphertext.len() - 32];
+        let forged = [&header.key_id[..], body].concat();
+        assert!(matches!(key.decrypt(&forged), Err(Error::WrongKey)));
+        assert!(!key.verify(forged.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_rejects_tampered_cyphertext() {
+        let key = Key::generate();
+        let mut cyphertext = key.encrypt(b"This is a secret").unwrap();
+        let last_block = cyphertext.len() - 40;
+        cyphertext[last_block] ^= 1;
+
+        assert!(matches!(key.decrypt(&cyphertext), Err(Error::Integrity)));
+        assert!(!key.verify(cyphertext.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_with_another_key_fails() {
+        let cyphertext = Key::generate().encrypt(b"This is a secret").unwrap();
+
+        assert!(matches!(
+            Key::generate().decrypt(&cyphertext),
+            Err(Error::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_input_is_an_error() {
+        assert!(matches!(b64decode(b"not base64!"), Err(Error::Decode(_))));
+        assert!(matches!(
+            Key::from_yaml(String::from("- nope")),
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            Key::generate().decrypt(b"short"),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable_and_short() {
+        let key = Key::generate();
+        let imported = Key::from_yaml(key.to_yaml().unwrap()).unwrap();
+        assert_equal!(key.fingerprint().unwrap(), imported.fingerprint().unwrap());
+        assert!(imported.created.is_some());
+        assert!(key.fingerprint().unwrap() != Key::generate().fingerprint().unwrap());
+    }
+
+    #[test]
+    fn test_rotate_file_to_a_new_key() {
+        let (old_key, new_key) = (Key::generate(), Key::generate());
+        let path = std::env::temp_dir().join(format!("rotate-{}.aes", std::process::id()));
+        let filename = path.to_str().unwrap();
+        std::fs::write(filename, old_key.encrypt(b"This is a secret").unwrap()).unwrap();
+
+        assert!(matches!(
+            new_key.rotate_file(&old_key, filename),
+            Err(Error::WrongKey)
+        ));
+        old_key.rotate_file(&new_key, filename).unwrap();
+        assert!(!old_key.owns_file(filename).unwrap());
+        let cyphertext = std::fs::read(filename).unwrap();
+        std::fs::remove_file(filename).unwrap();
+        assert_equal!(new_key.decrypt(&cyphertext).unwrap(), b"This is a secret");
+    }
+
+    #[test]
+    fn test_replace_file_then_shred_the_old_content() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+        let path = std::env::temp_dir().join(format!("shred-{}.txt", std::process::id()));
+        let filename = path.to_str().unwrap();
+        std::fs::write(filename, b"This is a secret").unwrap();
+
+        let mut old = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(filename)
+            .unwrap();
+        replace_file(filename, |file| Ok(file.write_all(b"Replaced")?)).unwrap();
+        shred(&mut old).unwrap();
+        let mut shredded = Vec::new();
+        old.seek(SeekFrom::Start(0)).unwrap();
+        old.read_to_end(&mut shredded).unwrap();
+        assert_equal!(shredded.len(), 16);
+        assert!(shredded != b"This is a secret");
+
+        assert_equal!(std::fs::read(filename).unwrap(), b"Replaced");
+        std::fs::remove_file(filename).unwrap();
+    }
 }
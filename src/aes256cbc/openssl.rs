@@ -0,0 +1,260 @@
+/*!
+OpenSSL `enc` compatibility
+
+Reads and writes the files of `openssl enc -aes-256-cbc`: the `Salted__`
+magic, an 8 byte random salt and the AES-256/CBC/Pkcs cyphertext, which
+carries no authentication tag. The cipher key and IV are derived from the
+password with PBKDF2-HMAC-SHA256, as with `-pbkdf2 -iter <n>`, or with one
+round of the legacy `EVP_BytesToKey`, as without `-pbkdf2`.
+
+`encrypting_writer` and `decrypting_reader` run the same loop as
+`EncryptingWriter` and `DecryptingReader`, so large files are processed
+with constant memory.
+
+# Example
+
+```
+use toolz::aes256cbc::openssl::{self, OpensslKdf};
+
+let kdf = OpensslKdf::Pbkdf2(openssl::PBKDF2_ITERATIONS);
+let cyphertext = openssl::encrypt(b"I <3 Nickelback", kdf, b"Some secret").unwrap();
+
+assert!(cyphertext.starts_with(openssl::MAGIC));
+assert_eq!(openssl::decrypt(b"I <3 Nickelback", kdf, &cyphertext).unwrap(), b"Some secret".to_vec());
+```
+*/
+use super::{DecryptingReader, EncryptingWriter, Error, CIPHER_KEY_SIZE, IV_SIZE};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::md5::Md5;
+use crypto::sha2::Sha256;
+use crypto::{aes, blockmodes, pbkdf2};
+use rand::RngCore;
+use std::io;
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+///The magic bytes starting the files of `openssl enc`
+pub const MAGIC: &[u8] = b"Salted__";
+///The default number of iterations of `openssl enc -pbkdf2`
+pub const PBKDF2_ITERATIONS: u32 = 10000;
+///The size of the random salt following the magic bytes
+const SALT_SIZE: usize = 8;
+
+/// How `openssl enc` derives the cipher key and IV from the password
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpensslKdf {
+    /// PBKDF2-HMAC-SHA256 with the given number of iterations
+    Pbkdf2(u32),
+    /// `EVP_BytesToKey` with SHA-256, the default of OpenSSL 1.1.0 and later
+    BytesToKeySha256,
+    /// `EVP_BytesToKey` with MD5, the default before OpenSSL 1.1.0
+    BytesToKeyMd5,
+}
+
+impl OpensslKdf {
+    /// The cipher key followed by the IV
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; CIPHER_KEY_SIZE + IV_SIZE]>, Error> {
+        let mut material = Zeroizing::new([0u8; CIPHER_KEY_SIZE + IV_SIZE]);
+        match *self {
+            OpensslKdf::Pbkdf2(0) => return Err(Error::kdf("PBKDF2 needs at least one iteration")),
+            OpensslKdf::Pbkdf2(iterations) => {
+                let mut mac = Hmac::new(Sha256::new(), password);
+                pbkdf2::pbkdf2(&mut mac, salt, iterations, &mut *material);
+            }
+            OpensslKdf::BytesToKeySha256 => {
+                bytes_to_key(Sha256::new(), password, salt, &mut *material)
+            }
+            OpensslKdf::BytesToKeyMd5 => bytes_to_key(Md5::new(), password, salt, &mut *material),
+        }
+        Ok(material)
+    }
+}
+
+/// Fills `material` with `D_1 || D_2 || ...` where
+/// `D_i = digest(D_(i-1) || password || salt)`
+fn bytes_to_key<D: Digest>(mut digest: D, password: &[u8], salt: &[u8], material: &mut [u8]) {
+    let mut block = Zeroizing::new(vec![0u8; digest.output_bytes()]);
+    let mut filled = 0;
+    while filled < material.len() {
+        digest.reset();
+        if filled > 0 {
+            digest.input(&block);
+        }
+        digest.input(password);
+        digest.input(salt);
+        digest.result(&mut block);
+        let size = block.len().min(material.len() - filled);
+        material[filled..filled + size].copy_from_slice(&block[..size]);
+        filled += size;
+    }
+}
+
+/// Wraps `inner` to encrypt like `openssl enc -aes-256-cbc`, writing the
+/// `Salted__` header with a random salt right away
+///
+/// `finish` must be called to write the padded final block.
+pub fn encrypting_writer<W: Write>(
+    password: &[u8],
+    kdf: OpensslKdf,
+    mut inner: W,
+) -> Result<EncryptingWriter<W>, Error> {
+    let mut salt = [0u8; SALT_SIZE];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let material = kdf.derive(password, &salt)?;
+    let (key, iv) = material.split_at(CIPHER_KEY_SIZE);
+    let encryptor = aes::cbc_encryptor(aes::KeySize::KeySize256, key, iv, blockmodes::PkcsPadding);
+    inner.write_all(MAGIC)?;
+    inner.write_all(&salt)?;
+    Ok(EncryptingWriter::unauthenticated(encryptor, inner))
+}
+
+/// Wraps `inner` to decrypt the output of `openssl enc -aes-256-cbc`,
+/// reading the `Salted__` header right away
+///
+/// Fails with `Error::Decode` if the header is missing. Reading usually
+/// fails with `Error::WrongKey` if the password or key derivation is not
+/// the one the data was encrypted with: without an authentication tag,
+/// only the padding tells, once the whole cyphertext was read.
+pub fn decrypting_reader<R: Read>(
+    password: &[u8],
+    kdf: OpensslKdf,
+    mut inner: R,
+) -> Result<DecryptingReader<R>, Error> {
+    let mut header = [0u8; MAGIC.len() + SALT_SIZE];
+    match inner.read_exact(&mut header) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(Error::decode("missing Salted__ header"))
+        }
+        result => result?,
+    }
+    let (magic, salt) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(Error::decode("missing Salted__ header"));
+    }
+    let material = kdf.derive(password, salt)?;
+    let (key, iv) = material.split_at(CIPHER_KEY_SIZE);
+    let decryptor = aes::cbc_decryptor(aes::KeySize::KeySize256, key, iv, blockmodes::PkcsPadding);
+    Ok(DecryptingReader::unauthenticated(decryptor, inner))
+}
+
+/// Encrypts a buffer like `openssl enc -aes-256-cbc` with a random salt
+///
+/// See `encrypting_writer` for encrypting data that does not fit in memory.
+pub fn encrypt(password: &[u8], kdf: OpensslKdf, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut writer = encrypting_writer(password, kdf, Vec::new())?;
+    writer.write_all(data)?;
+    writer.finish()
+}
+
+/// Decrypts the output of `openssl enc -aes-256-cbc`, failing like
+/// `decrypting_reader`
+///
+/// See `decrypting_reader` for decrypting data that does not fit in memory.
+pub fn decrypt(password: &[u8], kdf: OpensslKdf, cyphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut reader = decrypting_reader(password, kdf, cyphertext)?;
+    let mut plaintext = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::openssl::{
+        decrypt, decrypting_reader, encrypt, encrypting_writer, OpensslKdf,
+    };
+    use crate::aes256cbc::Error;
+    use k9::assert_equal;
+    use std::io::{Read, Write};
+    use std::process::{Command, Stdio};
+
+    /// Runs `openssl enc -aes-256-cbc` with the given arguments on `input`,
+    /// `None` if openssl is not installed
+    fn openssl_enc(args: &[&str], input: &[u8]) -> Option<Vec<u8>> {
+        let mut child = Command::new("openssl")
+            .args(["enc", "-aes-256-cbc", "-pass", "pass:I <3 Nickelback"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "openssl enc {:?} failed", args);
+        Some(output.stdout)
+    }
+
+    #[test]
+    fn test_openssl_interop() {
+        let plaintext: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let kdfs = [
+            (OpensslKdf::Pbkdf2(10000), vec!["-pbkdf2"]),
+            (OpensslKdf::Pbkdf2(1234), vec!["-pbkdf2", "-iter", "1234"]),
+            (OpensslKdf::BytesToKeySha256, vec!["-md", "sha256"]),
+            (OpensslKdf::BytesToKeyMd5, vec!["-md", "md5"]),
+        ];
+        for (kdf, args) in kdfs {
+            let cyphertext = encrypt(b"I <3 Nickelback", kdf, &plaintext).unwrap();
+            assert_equal!(
+                decrypt(b"I <3 Nickelback", kdf, &cyphertext).unwrap(),
+                plaintext
+            );
+
+            let decrypt_args = [args.as_slice(), &["-d"]].concat();
+            let from_openssl = match openssl_enc(&args, &plaintext) {
+                Some(cyphertext) => cyphertext,
+                None => return,
+            };
+            assert_equal!(
+                decrypt(b"I <3 Nickelback", kdf, &from_openssl).unwrap(),
+                plaintext
+            );
+            assert_equal!(openssl_enc(&decrypt_args, &cyphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_wrong_password_or_header() {
+        let kdf = OpensslKdf::Pbkdf2(10000);
+        let cyphertext = encrypt(b"I <3 Nickelback", kdf, b"Some secret").unwrap();
+        match decrypt(b"123456", kdf, &cyphertext) {
+            Ok(plaintext) => assert_ne!(plaintext, b"Some secret"),
+            Err(error) => assert!(matches!(error, Error::WrongKey)),
+        }
+        assert!(matches!(
+            decrypt(b"I <3 Nickelback", kdf, &cyphertext[8..]),
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            decrypt(b"I <3 Nickelback", OpensslKdf::Pbkdf2(0), &cyphertext),
+            Err(Error::Kdf(_))
+        ));
+    }
+
+    #[test]
+    fn test_openssl_stream_interoperates_with_buffer_api() {
+        let kdf = OpensslKdf::BytesToKeySha256;
+        let plaintext: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = encrypting_writer(b"I <3 Nickelback", kdf, Vec::new()).unwrap();
+        for chunk in plaintext.chunks(777) {
+            writer.write_all(chunk).unwrap();
+        }
+        let cyphertext = writer.finish().unwrap();
+        assert_equal!(
+            decrypt(b"I <3 Nickelback", kdf, &cyphertext).unwrap(),
+            plaintext
+        );
+
+        let cyphertext = encrypt(b"I <3 Nickelback", kdf, &plaintext).unwrap();
+        let mut reader = decrypting_reader(b"I <3 Nickelback", kdf, cyphertext.as_slice()).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_equal!(decrypted, plaintext);
+    }
+}
@@ -0,0 +1,211 @@
+/*!
+Passphrase-protected key files
+
+`Key::protect` wraps the `key` and `mac` material with XChaCha20-Poly1305
+under a key-encryption key derived from a passphrase, using the cycles and
+key derivation function of a `Config`. Protected key files hold no key
+material in the clear:
+
+```yaml
+algo: aes-256-cbc
+iv: ...
+protected:
+  cycles: {key: 1000, salt: 1000, iv: 1000}
+  kdf: pbkdf2
+  salt: ...
+  nonce: ...
+  material: ...
+  key_id: ...
+```
+
+The wrapped material is bound to the `algo` and `iv` of the key, so it
+cannot be moved to another key file. `Key::import` asks for the passphrase
+when it meets a protected key.
+
+The `key_id` is the public `Key::key_id()` written in the headers of the
+containers of the key, which lets a keyring tell if a protected key may own
+a file before asking for its passphrase.
+*/
+use super::aead::{open_chunk, seal_chunk};
+use super::format::{Cipher, Envelope};
+use super::{
+    b64decode, b64encode, generate_salt, Config, CyclesConfig, Error, KdfConfig, Key,
+    CIPHER_KEY_SIZE, KEY_SIZE,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use zeroize::Zeroizing;
+
+///The cipher wrapping the key material
+const WRAP_CIPHER: Cipher = Cipher::XChaCha20Poly1305;
+
+/// The key material of a protected key, wrapped with a passphrase
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
+pub struct Protection {
+    pub cycles: CyclesConfig,
+    pub kdf: KdfConfig,
+    pub salt: String,
+    pub nonce: String,
+    pub material: String,
+    /// The base64 `Key::key_id()`, `None` for keys protected before it
+    /// was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+}
+
+impl Protection {
+    /// The key-encryption key derived from the passphrase
+    fn kek(&self, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let config = Config {
+            cycles: self.cycles,
+            kdf: self.kdf,
+            default_key_path: None,
+        };
+        let derived = config.derive_key(passphrase, &b64decode(self.salt.as_bytes())?)?;
+        Ok(Zeroizing::new(derived[..CIPHER_KEY_SIZE].to_vec()))
+    }
+}
+
+/// What the wrapped material is bound to
+fn associated_data(key: &Key) -> Vec<u8> {
+    [key.algo.as_bytes(), b"\0", key.iv.as_bytes()].concat()
+}
+
+impl Key {
+    /// Whether the key material is wrapped with a passphrase
+    pub fn is_protected(&self) -> bool {
+        self.protected.is_some()
+    }
+    /// Wraps the key material with a passphrase, deriving the
+    /// key-encryption key with the given config
+    ///
+    /// The returned key is only good for `export` until `unprotect`ed.
+    pub fn protect(&self, passphrase: &[u8], config: &Config) -> Result<Key, Error> {
+        if self.is_protected() {
+            return Err(Error::decode("key is already protected"));
+        }
+        let mut protection = Protection {
+            cycles: config.cycles,
+            kdf: config.kdf,
+            salt: b64encode(&generate_salt()),
+            nonce: b64encode(&WRAP_CIPHER.generate_iv()),
+            material: String::new(),
+            key_id: Some(b64encode(&self.key_id()?)),
+        };
+        let material = Zeroizing::new([self.key_bytes()?.as_slice(), &self.mac_bytes()?].concat());
+        let wrapped = seal_chunk(
+            WRAP_CIPHER,
+            &protection.kek(passphrase)?,
+            &b64decode(protection.nonce.as_bytes())?,
+            &associated_data(self),
+            &material,
+        )?;
+        protection.material = b64encode(&wrapped);
+        let mut protected = self.clone();
+        protected.key = String::new();
+        protected.mac = String::new();
+        protected.protected = Some(protection);
+        Ok(protected)
+    }
+    /// Unwraps the key material of a protected key, failing with
+    /// `Error::Passphrase` if the passphrase is wrong
+    pub fn unprotect(&self, passphrase: &[u8]) -> Result<Key, Error> {
+        let protection = match self.protected {
+            Some(ref protection) => protection,
+            None => return Err(Error::decode("key is not protected")),
+        };
+        let material = Zeroizing::new(
+            open_chunk(
+                WRAP_CIPHER,
+                &protection.kek(passphrase)?,
+                &b64decode(protection.nonce.as_bytes())?,
+                &associated_data(self),
+                &b64decode(protection.material.as_bytes())?,
+            )
+            .map_err(|error| match error {
+                Error::Integrity => Error::Passphrase,
+                error => error,
+            })?,
+        );
+        if material.len() != KEY_SIZE {
+            return Err(Error::decode("invalid key material size"));
+        }
+        let mut unprotected = self.clone();
+        unprotected.key = b64encode(&material[..CIPHER_KEY_SIZE]);
+        unprotected.mac = b64encode(&material[CIPHER_KEY_SIZE..]);
+        unprotected.protected = None;
+        Ok(unprotected)
+    }
+    /// Whether a protected key may own an envelope, judging from the key id
+    /// recorded with its material, without asking for its passphrase
+    ///
+    /// Keys protected before the key id was recorded may own any envelope,
+    /// as may AES-256/CBC keys with the legacy envelopes that carry no key id.
+    pub fn may_own_envelope(&self, envelope: &Envelope) -> Result<bool, Error> {
+        let key_id = match self.protected {
+            Some(Protection {
+                key_id: Some(ref key_id),
+                ..
+            }) => b64decode(key_id.as_bytes())?,
+            _ => return Ok(true),
+        };
+        match envelope {
+            Envelope::Container(header) => Ok(header.is_for(&key_id, self.cipher()?)),
+            Envelope::Digest(_) => Ok(self.cipher()? == Cipher::Aes256CbcHmacSha256),
+        }
+    }
+    /// Load key from a YAML file, unwrapping it with the given
+    /// passphrase if it is protected
+    pub fn import_with_passphrase(filename: &str, passphrase: &[u8]) -> Result<Key, Error> {
+        let key = Key::from_yaml(fs::read_to_string(filename)?)?;
+        if key.is_protected() {
+            return key.unprotect(passphrase);
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::{Config, Error, Key};
+    use k9::assert_equal;
+
+    #[test]
+    fn test_protected_key_roundtrip() {
+        let config = Config::from_vec(&[10, 20, 30]);
+        let key = Key::generate();
+        let protected = key.protect(b"passphrase", &config).unwrap();
+
+        let yaml = protected.to_yaml().unwrap();
+        assert!(!yaml.contains(&key.key));
+        assert!(!yaml.contains(&key.mac));
+
+        let imported = Key::from_yaml(yaml).unwrap();
+        assert!(imported.is_protected());
+        let unprotected = imported.unprotect(b"passphrase").unwrap();
+        assert!(unprotected == key);
+
+        let cyphertext = key.encrypt(b"This is a secret").unwrap();
+        assert_equal!(
+            unprotected.decrypt(&cyphertext).unwrap(),
+            b"This is a secret"
+        );
+    }
+
+    #[test]
+    fn test_protected_key_rejects_wrong_passphrase_and_other_keys() {
+        let config = Config::from_vec(&[10, 20, 30]);
+        let protected = Key::generate().protect(b"passphrase", &config).unwrap();
+        assert!(matches!(
+            protected.unprotect(b"wrong"),
+            Err(Error::Passphrase)
+        ));
+
+        let mut moved = Key::generate().protect(b"passphrase", &config).unwrap();
+        moved.protected = protected.protected.clone();
+        assert!(matches!(
+            moved.unprotect(b"passphrase"),
+            Err(Error::Passphrase)
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+/*!
+Multi-recipient containers
+
+`Key::encrypt_for_recipients` and `EncryptingWriter::for_recipients`
+encrypt the data once with a random data key, using the cipher of the first
+recipient, and record that data key in the header wrapped for every
+recipient key. Any of the recipient keys decrypts the container with the
+usual `Key::decrypt`, `DecryptingReader` and `Key::verify`.
+
+The data key is wrapped with XChaCha20-Poly1305 under a key-encryption key
+derived from the mac key of the recipient, and bound to its key id.
+
+# Example
+
+```
+use toolz::aes256cbc::Key;
+
+let alice = Key::generate();
+let bob = Key::generate();
+let cyphertext = Key::encrypt_for_recipients(&[alice.clone(), bob.clone()], b"Team secret").unwrap();
+
+assert_eq!(alice.decrypt(&cyphertext).unwrap(), b"Team secret".to_vec());
+assert_eq!(bob.decrypt(&cyphertext).unwrap(), b"Team secret".to_vec());
+```
+*/
+use super::aead::{open_chunk, seal_chunk};
+use super::format::{Cipher, Header, Recipient, RECIPIENTS_FORMAT_VERSION};
+use super::{
+    b64encode, bytes_match, hmac_256_digest, EncryptingWriter, Error, Key, CIPHER_KEY_SIZE,
+    DIGEST_SIZE, IV_SIZE, KEY_SIZE,
+};
+use std::io::Write;
+use zeroize::Zeroizing;
+
+///The cipher wrapping the data key
+const WRAP_CIPHER: Cipher = Cipher::XChaCha20Poly1305;
+///Tells the key-encryption key of a recipient apart from its other digests
+const RECIPIENT_KEK_TAG: &[u8] = b"recipient-kek";
+
+impl Recipient {
+    /// Wraps the data key for the given recipient key
+    pub fn seal(recipient: &Key, data_key: &Key) -> Result<Recipient, Error> {
+        let key_id = recipient.key_id()?;
+        let nonce = WRAP_CIPHER.generate_iv();
+        let material = Zeroizing::new(
+            [
+                data_key.key_bytes()?.as_slice(),
+                &data_key.mac_bytes()?,
+                &data_key.iv_bytes()?,
+            ]
+            .concat(),
+        );
+        let wrapped = seal_chunk(
+            WRAP_CIPHER,
+            &recipient.recipient_kek()?[..],
+            &nonce,
+            &key_id,
+            &material,
+        )?;
+        Ok(Recipient {
+            key_id,
+            nonce,
+            wrapped,
+        })
+    }
+    /// Unwraps the data key with the recipient key, failing with
+    /// `Error::Integrity` if the slot was tampered with
+    pub fn open(&self, recipient: &Key, cipher: Cipher) -> Result<Key, Error> {
+        let material = Zeroizing::new(open_chunk(
+            WRAP_CIPHER,
+            &recipient.recipient_kek()?[..],
+            &self.nonce,
+            &self.key_id,
+            &self.wrapped,
+        )?);
+        if material.len() != KEY_SIZE + IV_SIZE {
+            return Err(Error::decode("invalid data key size"));
+        }
+        Ok(Key {
+            algo: String::from(cipher.name()),
+            key: b64encode(&material[..CIPHER_KEY_SIZE]),
+            mac: b64encode(&material[CIPHER_KEY_SIZE..KEY_SIZE]),
+            iv: b64encode(&material[KEY_SIZE..]),
+            magic: None,
+            kdf: None,
+            salt: None,
+            protected: None,
+            created: None,
+        })
+    }
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Wraps `inner`, encrypting with a random data key wrapped for each
+    /// of the `recipients`, with the cipher of the first one
+    pub fn for_recipients(recipients: &[Key], inner: W) -> Result<EncryptingWriter<W>, Error> {
+        let cipher = match recipients.first() {
+            Some(recipient) => recipient.cipher()?,
+            None => return Err(Error::unsupported("no recipients")),
+        };
+        let data_key = Key::generate().with_cipher(cipher);
+        let slots = recipients
+            .iter()
+            .map(|recipient| Recipient::seal(recipient, &data_key))
+            .collect::<Result<Vec<Recipient>, Error>>()?;
+        EncryptingWriter::with_recipients(&data_key, slots, &[], inner)
+    }
+}
+
+impl Key {
+    /// The key wrapping the data key of the containers sent to this key
+    fn recipient_kek(&self) -> Result<Zeroizing<[u8; DIGEST_SIZE]>, Error> {
+        Ok(Zeroizing::new(hmac_256_digest(
+            &self.mac_bytes()?,
+            RECIPIENT_KEK_TAG,
+        )))
+    }
+    /// The key that decrypts a container owned by this key: the key
+    /// itself, or the data key wrapped for it
+    pub(crate) fn container_key(&self, header: &Header) -> Result<Key, Error> {
+        if header.version != RECIPIENTS_FORMAT_VERSION {
+            return Ok(self.clone());
+        }
+        let key_id = self.key_id()?;
+        let recipient = header
+            .recipients
+            .iter()
+            .find(|recipient| bytes_match(&recipient.key_id, &key_id))
+            .ok_or(Error::WrongKey)?;
+        let data_key = recipient.open(self, header.cipher)?;
+        if !bytes_match(&header.key_id, &data_key.key_id()?) {
+            return Err(Error::Integrity);
+        }
+        Ok(data_key)
+    }
+    /// Encrypts a buffer once for several recipient keys, any of which
+    /// decrypts it
+    pub fn encrypt_for_recipients(recipients: &[Key], data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut writer = EncryptingWriter::for_recipients(recipients, Vec::new())?;
+        writer.write_all(data)?;
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::format::{Cipher, Envelope};
+    use crate::aes256cbc::{Error, Key};
+    use k9::assert_equal;
+
+    #[test]
+    fn test_any_recipient_decrypts() {
+        for cipher in Cipher::all() {
+            let alice = Key::generate().with_cipher(cipher);
+            let bob = Key::generate();
+            let recipients = [alice.clone(), bob.clone()];
+            let cyphertext = Key::encrypt_for_recipients(&recipients, b"Team secret").unwrap();
+
+            for key in &recipients {
+                assert_equal!(key.decrypt(&cyphertext).unwrap(), b"Team secret");
+                assert!(key.verify(cyphertext.as_slice()).unwrap());
+                match Envelope::read_from(&mut cyphertext.as_slice()).unwrap() {
+                    Envelope::Container(header) => assert!(key.owns_header(&header).unwrap()),
+                    Envelope::Digest(_) => panic!("expected a container"),
+                }
+            }
+            let eve = Key::generate().with_cipher(cipher);
+            assert!(matches!(eve.decrypt(&cyphertext), Err(Error::WrongKey)));
+        }
+    }
+
+    #[test]
+    fn test_tampered_recipient_slot_is_rejected() {
+        let alice = Key::generate();
+        let mut cyphertext =
+            Key::encrypt_for_recipients(&[alice.clone(), Key::generate()], b"Team secret").unwrap();
+        let header_size = match Envelope::read_from(&mut cyphertext.as_slice()).unwrap() {
+            Envelope::Container(header) => header.to_bytes().len(),
+            Envelope::Digest(_) => panic!("expected a container"),
+        };
+        // the last byte of the wrapped key of the second recipient
+        cyphertext[header_size - 1] ^= 1;
+        assert!(matches!(alice.decrypt(&cyphertext), Err(Error::Integrity)));
+
+        assert!(matches!(
+            Key::encrypt_for_recipients(&[], b"Team secret"),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
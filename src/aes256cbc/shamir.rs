@@ -0,0 +1,381 @@
+/*!
+Shamir secret sharing of keys
+
+`Key::split` splits a key into shares, any `threshold` of which recreate
+it with `Key::combine` while fewer tell nothing about it. Each byte of the
+secret, made of the cipher, the key material and the IV of the key followed
+by a checksum, is the constant term of a random polynomial of degree
+`threshold - 1` over GF(256), and share `i` holds the values at `x = i`.
+
+A share is written as a YAML file, or as 72 BIP39 words for printing, in
+three lines of 24 words that each carry a BIP39 checksum. Shares carry their
+own checksum too, and the combined secret is checked against its checksum,
+so that a corrupted share or a share of another split fails with
+`Error::Integrity` or `Error::Shares` rather than giving a wrong key.
+
+Only the cipher and the key material are shared: the combined key does not
+remember how the original key was derived nor when it was created.
+
+# Example
+
+```
+use toolz::aes256cbc::Key;
+
+let key = Key::generate();
+let shares = key.split(5, 3).unwrap();
+
+assert!(Key::combine(&shares[1..4]).unwrap() == key);
+assert!(Key::combine(&shares[..2]).is_err());
+```
+*/
+use super::format::Cipher;
+use super::keyring::write_private_file;
+use super::{
+    b64decode, b64encode, bytes_match, Error, Key, CIPHER_KEY_SIZE, DIGEST_SIZE, IV_SIZE, KEY_SIZE,
+};
+use bip39::{Language, Mnemonic};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+///The version of the layout of a share
+const SHARE_VERSION: u8 = 1;
+///The size of the random id telling the shares of a split apart
+const SET_ID_SIZE: usize = 4;
+///The size of the checksums of a share and of the shared secret
+const CHECKSUM_SIZE: usize = 4;
+///The size of the shared secret: cipher id, key material, IV and checksum
+const SECRET_SIZE: usize = 1 + KEY_SIZE + IV_SIZE + CHECKSUM_SIZE;
+///The size of a share: version, set id, threshold, index, its part of the
+///secret and checksum
+const SHARE_SIZE: usize = 3 + SET_ID_SIZE + SECRET_SIZE + CHECKSUM_SIZE;
+///The number of bytes written as each line of 24 BIP39 words
+const WORDS_LINE_SIZE: usize = 32;
+
+/// The first bytes of the SHA-256 of `data`
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut sha = Sha256::new();
+    sha.input(data);
+    let mut hash = [0; DIGEST_SIZE];
+    sha.result(&mut hash);
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&hash[..CHECKSUM_SIZE]);
+    checksum
+}
+
+/// Multiplies in GF(256) modulo the AES polynomial, without branching on
+/// secret values
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+        b >>= 1;
+    }
+    product
+}
+
+/// The inverse of a non-zero element of GF(256), `a^254`
+fn gf_inv(a: u8) -> u8 {
+    (0..254).fold(1, |inverse, _| gf_mul(inverse, a))
+}
+
+/// Evaluates the polynomial with the given coefficients, constant first
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |value, &coefficient| gf_mul(value, x) ^ coefficient)
+}
+
+/// One of the shares of a key split by `Key::split`
+///
+/// Its part of the secret is wiped from memory once dropped.
+#[derive(Clone, PartialEq)]
+pub struct Share {
+    /// Tells the shares of a split apart from the ones of other splits
+    pub set_id: [u8; SET_ID_SIZE],
+    /// The number of shares needed to recreate the key
+    pub threshold: u8,
+    /// The number of the share, from 1
+    pub index: u8,
+    data: Vec<u8>,
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+/// The YAML file of a share
+#[derive(Serialize, Deserialize)]
+struct ShareFile {
+    index: u8,
+    threshold: u8,
+    share: String,
+}
+
+impl Share {
+    /// Serializes the share, checksum included
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(SHARE_SIZE));
+        bytes.push(SHARE_VERSION);
+        bytes.extend_from_slice(&self.set_id);
+        bytes.push(self.threshold);
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.data);
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+    /// Reads a share, failing with `Error::Integrity` if its checksum
+    /// does not match
+    pub fn from_bytes(bytes: &[u8]) -> Result<Share, Error> {
+        if bytes.len() != SHARE_SIZE {
+            return Err(Error::decode("invalid share size"));
+        }
+        if bytes[0] != SHARE_VERSION {
+            return Err(Error::unsupported(format!("share version {}", bytes[0])));
+        }
+        let (content, sum) = bytes.split_at(SHARE_SIZE - CHECKSUM_SIZE);
+        if !bytes_match(&checksum(content), sum) {
+            return Err(Error::Integrity);
+        }
+        let mut set_id = [0; SET_ID_SIZE];
+        set_id.copy_from_slice(&content[1..1 + SET_ID_SIZE]);
+        let share = Share {
+            set_id,
+            threshold: content[1 + SET_ID_SIZE],
+            index: content[2 + SET_ID_SIZE],
+            data: content[3 + SET_ID_SIZE..].to_vec(),
+        };
+        if share.index == 0 || share.threshold < 2 {
+            return Err(Error::decode("invalid share"));
+        }
+        Ok(share)
+    }
+    /// Serializes the share into a YAML string
+    pub fn to_yaml(&self) -> Result<String, Error> {
+        let file = ShareFile {
+            index: self.index,
+            threshold: self.threshold,
+            share: b64encode(&self.to_bytes()),
+        };
+        Ok(serde_yaml::to_string(&file)?)
+    }
+    /// Writes the share as lines of 24 BIP39 words
+    pub fn to_words(&self) -> Result<String, Error> {
+        let lines = self
+            .to_bytes()
+            .chunks(WORDS_LINE_SIZE)
+            .map(|line| {
+                Mnemonic::from_entropy(line, Language::English)
+                    .map(|mnemonic| mnemonic.phrase().to_string())
+                    .map_err(Error::decode)
+            })
+            .collect::<Result<Vec<String>, Error>>()?;
+        Ok(lines.join("\n") + "\n")
+    }
+    /// Reads a share written by `to_yaml` or `to_words`
+    pub fn from_text(text: &str) -> Result<Share, Error> {
+        if let Ok(file) = serde_yaml::from_str::<ShareFile>(text) {
+            let share = Share::from_bytes(&Zeroizing::new(b64decode(file.share.as_bytes())?))?;
+            if (share.index, share.threshold) != (file.index, file.threshold) {
+                return Err(Error::Integrity);
+            }
+            return Ok(share);
+        }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.len() != SHARE_SIZE / WORDS_LINE_SIZE * 24 {
+            return Err(Error::decode("a share is 72 words long"));
+        }
+        let mut bytes = Zeroizing::new(Vec::with_capacity(SHARE_SIZE));
+        for line in words.chunks(24) {
+            let phrase = Zeroizing::new(line.join(" ").to_lowercase());
+            let mnemonic = Mnemonic::from_phrase(&phrase, Language::English)
+                .map_err(|error| Error::decode(format!("invalid share words: {}", error)))?;
+            bytes.extend_from_slice(mnemonic.entropy());
+        }
+        Share::from_bytes(&bytes)
+    }
+    /// Stores the share into a new file only readable by its owner, in
+    /// YAML or as BIP39 words
+    pub fn export(&self, filename: &str, words: bool) -> Result<String, Error> {
+        let text = Zeroizing::new(if words {
+            self.to_words()?
+        } else {
+            self.to_yaml()?
+        });
+        write_private_file(Path::new(filename), &text)?;
+        Ok(String::from(filename))
+    }
+}
+
+impl Key {
+    /// Splits the key into `shares` shares, any `threshold` of which
+    /// recreate it with `Key::combine`
+    ///
+    /// Protected keys must be unprotected first.
+    pub fn split(&self, shares: u8, threshold: u8) -> Result<Vec<Share>, Error> {
+        if threshold < 2 || shares < threshold {
+            return Err(Error::shares(
+                "the threshold must be at least 2 and at most the number of shares",
+            ));
+        }
+        let mut secret = Zeroizing::new(Vec::with_capacity(SECRET_SIZE));
+        secret.push(self.cipher()?.id());
+        secret.extend_from_slice(&self.key_bytes()?);
+        secret.extend_from_slice(&self.mac_bytes()?);
+        secret.extend_from_slice(&self.iv_bytes()?);
+        if secret.len() != SECRET_SIZE - CHECKSUM_SIZE {
+            return Err(Error::decode("invalid key material size"));
+        }
+        let sum = checksum(&secret);
+        secret.extend_from_slice(&sum);
+
+        let mut rng = rand::thread_rng();
+        let mut set_id = [0; SET_ID_SIZE];
+        rng.fill_bytes(&mut set_id);
+        let mut data: Vec<Zeroizing<Vec<u8>>> = (0..shares)
+            .map(|_| Zeroizing::new(Vec::with_capacity(SECRET_SIZE)))
+            .collect();
+        let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+        for &byte in secret.iter() {
+            coefficients[0] = byte;
+            rng.fill_bytes(&mut coefficients[1..]);
+            for (x, share) in (1..=shares).zip(data.iter_mut()) {
+                share.push(evaluate(&coefficients, x));
+            }
+        }
+        Ok((1..=shares)
+            .zip(data)
+            .map(|(index, data)| Share {
+                set_id,
+                threshold,
+                index,
+                data: data.to_vec(),
+            })
+            .collect())
+    }
+    /// Recreates a key from at least `threshold` of its shares
+    ///
+    /// Fails with `Error::Shares` if there are too few shares or shares of
+    /// different splits, and with `Error::Integrity` if the combined key
+    /// does not match its checksum.
+    pub fn combine(shares: &[Share]) -> Result<Key, Error> {
+        let first = shares.first().ok_or_else(|| Error::shares("no shares"))?;
+        if shares
+            .iter()
+            .any(|share| share.set_id != first.set_id || share.threshold != first.threshold)
+        {
+            return Err(Error::shares("the shares come from different splits"));
+        }
+        if shares.len() < first.threshold as usize {
+            return Err(Error::shares(format!(
+                "{} shares are needed, {} given",
+                first.threshold,
+                shares.len()
+            )));
+        }
+        let shares = &shares[..first.threshold as usize];
+        for (position, share) in shares.iter().enumerate() {
+            if shares[..position]
+                .iter()
+                .any(|other| other.index == share.index)
+            {
+                return Err(Error::shares(format!("share {} given twice", share.index)));
+            }
+        }
+
+        // the Lagrange basis polynomials of the shares at x = 0
+        let basis: Vec<u8> = shares
+            .iter()
+            .map(|share| {
+                shares
+                    .iter()
+                    .filter(|other| other.index != share.index)
+                    .fold(1, |product, other| {
+                        gf_mul(
+                            product,
+                            gf_mul(other.index, gf_inv(other.index ^ share.index)),
+                        )
+                    })
+            })
+            .collect();
+        let secret = Zeroizing::new(
+            (0..SECRET_SIZE)
+                .map(|position| {
+                    shares.iter().zip(&basis).fold(0, |byte, (share, &l)| {
+                        byte ^ gf_mul(share.data[position], l)
+                    })
+                })
+                .collect::<Vec<u8>>(),
+        );
+        let (secret, sum) = secret.split_at(SECRET_SIZE - CHECKSUM_SIZE);
+        if !bytes_match(&checksum(secret), sum) {
+            return Err(Error::Integrity);
+        }
+        let cipher = Cipher::from_id(secret[0])
+            .ok_or_else(|| Error::decode(format!("unknown cipher id: {}", secret[0])))?;
+        let material = &secret[1..];
+        Ok(Key {
+            algo: String::from(cipher.name()),
+            key: b64encode(&material[..CIPHER_KEY_SIZE]),
+            mac: b64encode(&material[CIPHER_KEY_SIZE..KEY_SIZE]),
+            iv: b64encode(&material[KEY_SIZE..]),
+            magic: None,
+            kdf: None,
+            salt: None,
+            protected: None,
+            created: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::format::Cipher;
+    use crate::aes256cbc::shamir::Share;
+    use crate::aes256cbc::{Error, Key};
+    use k9::assert_equal;
+
+    #[test]
+    fn test_any_threshold_shares_recreate_the_key() {
+        let key = Key::generate().with_cipher(Cipher::ChaCha20Poly1305);
+        let shares = key.split(5, 3).unwrap();
+        let cyphertext = key.encrypt(b"Disaster recovery").unwrap();
+
+        for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let picked: Vec<Share> = picked.iter().map(|&i| shares[i].clone()).collect();
+            let combined = Key::combine(&picked).unwrap();
+            assert_equal!(combined.decrypt(&cyphertext).unwrap(), b"Disaster recovery");
+        }
+        let yaml = Share::from_text(&shares[0].to_yaml().unwrap()).unwrap();
+        let words = Share::from_text(&shares[1].to_words().unwrap()).unwrap();
+        assert!(Key::combine(&[yaml, words, shares[2].clone()]).unwrap() == key);
+
+        assert!(matches!(Key::combine(&shares[..2]), Err(Error::Shares(_))));
+        let other = key.split(5, 3).unwrap();
+        assert!(matches!(
+            Key::combine(&[shares[0].clone(), shares[1].clone(), other[2].clone()]),
+            Err(Error::Shares(_))
+        ));
+    }
+
+    #[test]
+    fn test_bad_share_is_detected() {
+        let shares = Key::generate().split(3, 2).unwrap();
+        let mut bytes = shares[0].to_bytes();
+        bytes[20] ^= 1;
+        assert!(matches!(Share::from_bytes(&bytes), Err(Error::Integrity)));
+
+        let words = shares[0].to_words().unwrap();
+        let first = words.split_whitespace().next().unwrap();
+        let swapped = words.replacen(first, if first == "zoo" { "abandon" } else { "zoo" }, 1);
+        assert!(Share::from_text(&swapped).is_err());
+    }
+}
@@ -0,0 +1,491 @@
+/*!
+Streaming adapters for `Key`
+
+`EncryptingWriter` and `DecryptingReader` run the same AES-256/CBC/Pkcs
+loop as `Key::encrypt` and `Key::decrypt` but over `std::io::Write` and
+`std::io::Read`, so arbitrarily large files can be processed with
+constant memory. Keys using an AEAD cipher are processed in chunks instead.
+
+An AES-256/CBC stream can only be authenticated once it has been read to
+the end, so `DecryptingReader` fails on a bad tag before releasing the last
+block. Use `Key::verify` first when nothing may be decrypted from tampered
+data. AEAD chunks are authenticated before being released.
+
+Errors coming out of `std::io::Read` and `std::io::Write` wrap an `Error`,
+which `Error::from` recovers.
+
+# Example
+
+```
+use std::io::{Read, Write};
+use toolz::aes256cbc::{generate_salt, Config, DecryptingReader, EncryptingWriter, Key};
+
+let config = Config::from_vec(&[100, 200, 300]);
+let key = Key::from_password(b"I <3 Nickelback", &generate_salt(), &config).unwrap();
+
+let mut writer = EncryptingWriter::new(&key, Vec::new()).unwrap();
+writer.write_all(b"Some secret information").unwrap();
+let cyphertext = writer.finish().unwrap();
+
+let mut reader = DecryptingReader::new(&key, cyphertext.as_slice()).unwrap();
+let mut plaintext = Vec::new();
+reader.read_to_end(&mut plaintext).unwrap();
+
+assert_eq!(b"Some secret information".to_vec(), plaintext);
+```
+*/
+use super::aead::{Opener, Sealer};
+use super::format::{Cipher, Envelope, Header, Recipient};
+use super::{aead, bytes_match, Error, Key, BUF_SIZE, DIGEST_SIZE};
+use crypto::buffer::{BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::{Decryptor, Encryptor, SymmetricCipherError};
+use std::io;
+use std::io::{Read, Write};
+
+/// Computes the HMAC of a cyphertext as it is read, holding back
+/// the trailing bytes that might be its authentication tag.
+struct Authenticator {
+    mac: Hmac<Sha256>,
+    tail: Vec<u8>,
+}
+
+impl Authenticator {
+    fn new(key: &Key, authenticated: &[u8]) -> Result<Authenticator, Error> {
+        let mut mac = Hmac::new(Sha256::new(), &key.mac_bytes()?);
+        mac.input(authenticated);
+        Ok(Authenticator {
+            mac,
+            tail: Vec::with_capacity(BUF_SIZE + DIGEST_SIZE),
+        })
+    }
+    /// Authenticates `chunk`, returning the bytes known not to be part of the tag
+    fn update(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.tail.extend_from_slice(chunk);
+        let split = self.tail.len().saturating_sub(DIGEST_SIZE);
+        let body: Vec<u8> = self.tail.drain(..split).collect();
+        self.mac.input(&body);
+        body
+    }
+    /// Checks the held back tag once the whole cyphertext went through `update`
+    fn verify(mut self) -> bool {
+        let result = self.mac.result();
+        bytes_match(&self.tail, result.code())
+    }
+}
+
+/// Feeds `input` through an encryptor or decryptor step function,
+/// handing every produced chunk to `output`.
+pub(super) fn pump<F, O>(mut step: F, input: &[u8], eof: bool, mut output: O) -> io::Result<()>
+where
+    F: FnMut(
+        &mut RefReadBuffer,
+        &mut RefWriteBuffer,
+        bool,
+    ) -> Result<BufferResult, SymmetricCipherError>,
+    O: FnMut(&[u8]) -> io::Result<()>,
+{
+    let mut read_buffer = RefReadBuffer::new(input);
+    let mut buffer = [0; BUF_SIZE];
+    let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+    loop {
+        let result = step(&mut read_buffer, &mut write_buffer, eof).map_err(Error::Cipher)?;
+        output(write_buffer.take_read_buffer().take_remaining())?;
+        match result {
+            BufferResult::BufferUnderflow => break,
+            BufferResult::BufferOverflow => {}
+        }
+    }
+    Ok(())
+}
+
+/// How an `EncryptingWriter` encrypts, according to the cipher of the key
+enum Sealing {
+    /// AES-256/CBC/Pkcs, with the HMAC of everything written so far
+    /// unless it writes no authentication tag
+    Cbc {
+        encryptor: Box<dyn Encryptor>,
+        mac: Option<Hmac<Sha256>>,
+    },
+    /// AEAD chunks
+    Aead(Sealer),
+}
+
+/// Encrypts everything written to it into the inner writer.
+///
+/// The container header with a random IV is written upon creation, the final
+/// padded block and the authentication tag are only written by `finish()`.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    sealing: Sealing,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Wraps `inner`, writing the container header right away
+    pub fn new(key: &Key, inner: W) -> Result<EncryptingWriter<W>, Error> {
+        EncryptingWriter::with_aad(key, &[], inner)
+    }
+    /// Wraps `inner`, authenticating the associated data `aad`, which
+    /// requires an AEAD cipher unless it is empty
+    pub fn with_aad(key: &Key, aad: &[u8], inner: W) -> Result<EncryptingWriter<W>, Error> {
+        EncryptingWriter::with_recipients(key, Vec::new(), aad, inner)
+    }
+    /// Wraps `inner`, recording the recipient slots of `key` in the header
+    /// unless there are none
+    pub(crate) fn with_recipients(
+        key: &Key,
+        recipients: Vec<Recipient>,
+        aad: &[u8],
+        mut inner: W,
+    ) -> Result<EncryptingWriter<W>, Error> {
+        let cipher = key.cipher()?;
+        let iv = cipher.generate_iv();
+        let mut header = Header::new(key, &iv)?;
+        if !recipients.is_empty() {
+            header = header.with_recipients(recipients)?;
+        }
+        let header = header.to_bytes();
+        let sealing = match cipher {
+            Cipher::Aes256CbcHmacSha256 if aad.is_empty() => {
+                let mut mac = Hmac::new(Sha256::new(), &key.mac_bytes()?);
+                mac.input(&header);
+                Sealing::Cbc {
+                    encryptor: key.encryptor(&iv)?,
+                    mac: Some(mac),
+                }
+            }
+            Cipher::Aes256CbcHmacSha256 => {
+                return Err(Error::unsupported(
+                    "associated data requires an AEAD cipher",
+                ))
+            }
+            cipher => Sealing::Aead(Sealer::new(
+                cipher,
+                &key.key_bytes()?,
+                &iv,
+                &[&header, aad].concat(),
+            )?),
+        };
+        inner.write_all(&header)?;
+        Ok(EncryptingWriter { inner, sealing })
+    }
+    /// Wraps `inner` with a bare AES-256/CBC/Pkcs encryptor, writing
+    /// neither a header nor an authentication tag
+    pub(super) fn unauthenticated(encryptor: Box<dyn Encryptor>, inner: W) -> EncryptingWriter<W> {
+        EncryptingWriter {
+            inner,
+            sealing: Sealing::Cbc {
+                encryptor,
+                mac: None,
+            },
+        }
+    }
+    /// Writes the padded final block and the authentication tag,
+    /// returning the inner writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.process(&[], true)?;
+        match self.sealing {
+            Sealing::Cbc { mac: None, .. } => {}
+            Sealing::Cbc {
+                mac: Some(mut mac), ..
+            } => {
+                let tag = mac.result();
+                self.inner.write_all(tag.code())?;
+            }
+            Sealing::Aead(sealer) => self.inner.write_all(&sealer.finish()?)?,
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+    fn process(&mut self, input: &[u8], eof: bool) -> io::Result<()> {
+        let inner = &mut self.inner;
+        match self.sealing {
+            Sealing::Cbc {
+                ref mut encryptor,
+                ref mut mac,
+            } => pump(
+                |input, output, eof| encryptor.encrypt(input, output, eof),
+                input,
+                eof,
+                |chunk| {
+                    if let Some(mac) = mac {
+                        mac.input(chunk);
+                    }
+                    inner.write_all(chunk)
+                },
+            ),
+            Sealing::Aead(ref mut sealer) => inner.write_all(&sealer.update(input)?),
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.process(buf, false)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How a `DecryptingReader` decrypts, according to the header
+enum Opening {
+    /// AES-256/CBC/Pkcs, authenticated unless it is a legacy cyphertext
+    Cbc {
+        decryptor: Box<dyn Decryptor>,
+        authenticator: Option<Authenticator>,
+    },
+    /// AEAD chunks, `None` once the last one was opened
+    Aead(Option<Opener>),
+}
+
+/// Decrypts the cyphertext read from the inner reader.
+///
+/// Reading fails with `Error::Integrity` when the authentication tag
+/// does not match, and with `Error::WrongKey` when the padding is wrong.
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    opening: Opening,
+    plaintext: Vec<u8>,
+    position: usize,
+    eof: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Wraps `inner`, reading and checking the header right away
+    pub fn new(key: &Key, inner: R) -> Result<DecryptingReader<R>, Error> {
+        DecryptingReader::with_aad(key, &[], inner)
+    }
+    /// Wraps `inner`, checking the associated data `aad` given to
+    /// `EncryptingWriter::with_aad`
+    pub fn with_aad(key: &Key, aad: &[u8], inner: R) -> Result<DecryptingReader<R>, Error> {
+        DecryptingReader::open(key, aad, inner, false)
+    }
+    /// Wraps `inner`, also decrypting the legacy cyphertexts using the key's
+    /// own IV, which `new` refuses with `Error::Unauthenticated`
+    ///
+    /// Nothing detects tampering with such cyphertexts: anyone who saw one
+    /// can forge others that decrypt without error.
+    pub fn with_unauthenticated_legacy(key: &Key, inner: R) -> Result<DecryptingReader<R>, Error> {
+        DecryptingReader::open(key, &[], inner, true)
+    }
+    fn open(
+        key: &Key,
+        aad: &[u8],
+        mut inner: R,
+        allow_unauthenticated: bool,
+    ) -> Result<DecryptingReader<R>, Error> {
+        let envelope = Envelope::read_from(&mut inner)?;
+        let opened = key.open(&envelope, &mut inner, allow_unauthenticated)?;
+        let key = &opened.key;
+        let opening = match (opened.cipher, opened.authenticated) {
+            (cipher, Some(header)) if cipher.is_aead() => Opening::Aead(Some(Opener::new(
+                cipher,
+                &key.key_bytes()?,
+                &opened.iv,
+                &[&header, aad].concat(),
+            )?)),
+            (_, authenticated) if aad.is_empty() => Opening::Cbc {
+                decryptor: key.decryptor(&opened.iv)?,
+                authenticator: match authenticated {
+                    Some(authenticated) => Some(Authenticator::new(key, &authenticated)?),
+                    None => None,
+                },
+            },
+            _ => {
+                return Err(Error::unsupported(
+                    "associated data requires an AEAD cipher",
+                ))
+            }
+        };
+        Ok(DecryptingReader {
+            inner,
+            opening,
+            plaintext: Vec::new(),
+            position: 0,
+            eof: false,
+        })
+    }
+    /// Wraps `inner` with a bare AES-256/CBC/Pkcs decryptor, reading
+    /// neither a header nor an authentication tag
+    pub(super) fn unauthenticated(decryptor: Box<dyn Decryptor>, inner: R) -> DecryptingReader<R> {
+        DecryptingReader {
+            inner,
+            opening: Opening::Cbc {
+                decryptor,
+                authenticator: None,
+            },
+            plaintext: Vec::new(),
+            position: 0,
+            eof: false,
+        }
+    }
+    /// Returns the inner reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+    fn fill(&mut self) -> io::Result<()> {
+        let mut cyphertext = [0; BUF_SIZE];
+        self.plaintext.clear();
+        self.position = 0;
+        while self.plaintext.is_empty() && !self.eof {
+            let count = self.inner.read(&mut cyphertext)?;
+            self.eof = count == 0;
+            let plaintext = &mut self.plaintext;
+            match self.opening {
+                Opening::Cbc {
+                    ref mut decryptor,
+                    ref mut authenticator,
+                } => {
+                    let body = match authenticator {
+                        Some(ref mut authenticator) => authenticator.update(&cyphertext[..count]),
+                        None => cyphertext[..count].to_vec(),
+                    };
+                    if self.eof {
+                        if let Some(authenticator) = authenticator.take() {
+                            if !authenticator.verify() {
+                                return Err(Error::Integrity.into());
+                            }
+                        }
+                    }
+                    // once the tag matched, only a wrong key breaks the
+                    // padding, which is all that tells without a tag
+                    pump(
+                        |input, output, eof| decryptor.decrypt(input, output, eof),
+                        &body,
+                        self.eof,
+                        |chunk| {
+                            plaintext.extend_from_slice(chunk);
+                            Ok(())
+                        },
+                    )
+                    .map_err(|error| match Error::from(error) {
+                        Error::Cipher(SymmetricCipherError::InvalidPadding) => Error::WrongKey,
+                        error => error,
+                    })?;
+                }
+                Opening::Aead(ref mut opener) => {
+                    if let Some(ref mut chunks) = opener {
+                        plaintext.extend(chunks.update(&cyphertext[..count])?);
+                    }
+                    if self.eof {
+                        if let Some(chunks) = opener.take() {
+                            plaintext.extend(chunks.finish()?);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Key {
+    /// Checks the authentication tag of a cyphertext without decrypting it
+    ///
+    /// Fails with `Error::Unauthenticated` on legacy cyphertexts using the
+    /// key's own IV, which carry no authentication tag to check. AEAD
+    /// cyphertexts are decrypted chunk by chunk and must not have
+    /// associated data.
+    pub fn verify<R: Read>(&self, mut reader: R) -> Result<bool, Error> {
+        let envelope = Envelope::read_from(&mut reader)?;
+        let opened = match self.open(&envelope, &mut reader, false) {
+            Ok(opened) => opened,
+            Err(Error::WrongKey) | Err(Error::Integrity) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let key = &opened.key;
+        if opened.cipher.is_aead() {
+            let header = opened.authenticated.unwrap_or_default();
+            let opener = Opener::new(opened.cipher, &key.key_bytes()?, &opened.iv, &header)?;
+            return verify_chunks(opener, reader);
+        }
+        let authenticated = match opened.authenticated {
+            Some(authenticated) => authenticated,
+            None => return Err(Error::Unauthenticated),
+        };
+        let mut authenticator = Authenticator::new(key, &authenticated)?;
+        let mut buffer = [0; BUF_SIZE];
+        loop {
+            let count = reader.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            authenticator.update(&buffer[..count]);
+        }
+        Ok(authenticator.verify())
+    }
+}
+
+/// Opens every AEAD chunk of `reader`, discarding the plaintext
+fn verify_chunks<R: Read>(mut opener: Opener, mut reader: R) -> Result<bool, Error> {
+    let mut buffer = [0; aead::CHUNK_SIZE];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        match opener.update(&buffer[..count]) {
+            Err(Error::Integrity) => return Ok(false),
+            result => result?,
+        };
+    }
+    match opener.finish() {
+        Err(Error::Integrity) => Ok(false),
+        result => result.map(|_| true),
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.plaintext.len() {
+            self.fill()?;
+        }
+        let available = &self.plaintext[self.position..];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::{generate_salt, Config, DecryptingReader, EncryptingWriter, Error, Key};
+    use k9::assert_equal;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_stream_interoperates_with_buffer_api() {
+        let config = Config::builtin(None);
+        let key = Key::from_password(b"123456", &generate_salt(), &config).unwrap();
+        let plaintext: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = EncryptingWriter::new(&key, Vec::new()).unwrap();
+        for chunk in plaintext.chunks(777) {
+            writer.write_all(chunk).unwrap();
+        }
+        let cyphertext = writer.finish().unwrap();
+        assert_equal!(key.decrypt(&cyphertext).unwrap(), plaintext);
+
+        let mut reader = DecryptingReader::new(&key, cyphertext.as_slice()).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_equal!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_reader_rejects_tampered_stream() {
+        let key = Key::generate();
+        let mut cyphertext = key.encrypt(&[7; 10000]).unwrap();
+        cyphertext[100] ^= 1;
+
+        let mut reader = DecryptingReader::new(&key, cyphertext.as_slice()).unwrap();
+        let mut decrypted = Vec::new();
+        let error = reader.read_to_end(&mut decrypted).unwrap_err();
+        assert!(matches!(Error::from(error), Error::Integrity));
+    }
+}
@@ -0,0 +1,391 @@
+/*!
+Directory trees encrypted file by file
+
+`Key::encrypt_tree` encrypts every regular file under a directory in place,
+skipping the files the key already owns, and `Key::decrypt_tree` decrypts
+them back. Symbolic links are not followed, and hidden files and
+directories are left alone unless asked for.
+
+Before any file is replaced, an encrypted manifest is written at the root of
+the tree as `.aes256cbc-manifest`, recording the path, size, permissions and
+modification time of each file, which `decrypt_tree` restores before
+deleting the manifest, or keeping only the entries of the files it skipped.
+Encrypting a tree again, for instance after an interruption, keeps the
+entries of the files that were already encrypted.
+
+# Example
+
+```
+use std::fs;
+use toolz::aes256cbc::Key;
+
+let root = std::env::temp_dir().join(format!("tree-doc-{}", std::process::id()));
+fs::create_dir_all(root.join("src")).unwrap();
+fs::write(root.join("src/main.rs"), b"fn main() {}").unwrap();
+
+let key = Key::generate();
+key.encrypt_tree(&root, false).unwrap();
+assert!(key.owns_file(root.join("src/main.rs").to_str().unwrap()).unwrap());
+
+key.decrypt_tree(&root).unwrap();
+assert_eq!(fs::read(root.join("src/main.rs")).unwrap(), b"fn main() {}".to_vec());
+fs::remove_dir_all(root).unwrap();
+```
+*/
+use super::{armor, read_bytes, replace_file, DecryptingReader, EncryptingWriter, Error, Key};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+///The name of the encrypted manifest at the root of an encrypted tree
+pub const MANIFEST_FILENAME: &str = ".aes256cbc-manifest";
+
+/// The metadata of a file of an encrypted tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// The path of the file relative to the root, `/`-separated
+    pub path: String,
+    /// The size of the plaintext
+    pub size: u64,
+    /// The permission bits of the file
+    pub mode: u32,
+    /// The modification time, in seconds since the Unix epoch
+    pub modified: u64,
+    /// The sub-second part of the modification time
+    pub modified_nanos: u32,
+}
+
+/// The files of an encrypted tree, stored encrypted with its key
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+/// The files processed and skipped by `Key::encrypt_tree` or
+/// `Key::decrypt_tree`, relative to the root
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TreeSummary {
+    pub processed: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+pub(super) fn path_str(path: &Path) -> Result<&str, Error> {
+    path.to_str()
+        .ok_or_else(|| Error::decode(format!("not a UTF-8 path: {}", path.display())))
+}
+
+#[cfg(unix)]
+pub(super) fn mode_of(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+#[cfg(not(unix))]
+pub(super) fn mode_of(metadata: &fs::Metadata) -> u32 {
+    match metadata.permissions().readonly() {
+        true => 0o444,
+        false => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// The modification time of a file, since the Unix epoch
+pub(super) fn modified_of(metadata: &fs::Metadata) -> Result<Duration, Error> {
+    Ok(metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default())
+}
+
+/// Sets the modification time and permission bits of a file or directory
+///
+/// The permissions are set last, as the file is opened to set its
+/// modification time, which a mode without owner read would prevent.
+pub(super) fn restore_metadata(path: &Path, mode: u32, modified: Duration) -> Result<(), Error> {
+    set_mode(path, mode | 0o400)?;
+    File::open(path)?.set_modified(UNIX_EPOCH + modified)?;
+    set_mode(path, mode)?;
+    Ok(())
+}
+
+/// Joins a `/`-separated relative path to `root`, refusing empty, absolute
+/// or `..` paths that would leave it
+pub(super) fn safe_join(root: &Path, relative: &str) -> Result<PathBuf, Error> {
+    let path = Path::new(relative);
+    let safe = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !safe || relative.is_empty() {
+        return Err(Error::decode(format!("unsafe path: {}", relative)));
+    }
+    Ok(root.join(path))
+}
+
+impl ManifestEntry {
+    /// Records the current metadata of the file at `relative` under `root`
+    pub fn of(root: &Path, relative: &str) -> Result<ManifestEntry, Error> {
+        let metadata = fs::metadata(root.join(relative))?;
+        let modified = modified_of(&metadata)?;
+        Ok(ManifestEntry {
+            path: String::from(relative),
+            size: metadata.len(),
+            mode: mode_of(&metadata),
+            modified: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+    /// The path of the file under `root`, refusing paths that would leave it
+    pub fn path_in(&self, root: &Path) -> Result<PathBuf, Error> {
+        safe_join(root, &self.path)
+    }
+    /// Restores the permissions and modification time of the file
+    pub fn restore(&self, root: &Path) -> Result<(), Error> {
+        restore_metadata(
+            &self.path_in(root)?,
+            self.mode,
+            Duration::new(self.modified, self.modified_nanos),
+        )
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest of the tree at `root`, `None` if there is none
+    ///
+    /// Fails with `Error::WrongKey` if it was not encrypted by `key`.
+    pub fn read(root: &Path, key: &Key) -> Result<Option<Manifest>, Error> {
+        let path = root.join(MANIFEST_FILENAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let yaml = key.decrypt(&read_bytes(path_str(&path)?)?)?;
+        Ok(Some(serde_yaml::from_slice(&yaml)?))
+    }
+    /// Encrypts the manifest into the root of the tree, replacing the
+    /// previous one only once complete
+    pub fn write(&self, root: &Path, key: &Key) -> Result<(), Error> {
+        let cyphertext = key.encrypt(serde_yaml::to_string(self)?.as_bytes())?;
+        replace_file(path_str(&root.join(MANIFEST_FILENAME))?, |file| {
+            Ok(file.write_all(&cyphertext)?)
+        })
+    }
+}
+
+/// Lists the regular files under `root` as sorted `/`-separated relative
+/// paths, without following symbolic links nor listing the manifest
+pub fn list_files(root: &Path, include_hidden: bool) -> Result<Vec<String>, Error> {
+    let mut files = Vec::new();
+    walk(root, Path::new(""), include_hidden, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Recurses into directories with `read_dir` like `slugify-filenames`,
+/// but without following symbolic links to directories
+fn walk(
+    root: &Path,
+    relative: &Path,
+    include_hidden: bool,
+    files: &mut Vec<String>,
+) -> Result<(), Error> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if (hidden && !include_hidden) || path == Path::new(MANIFEST_FILENAME) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(root, &path, include_hidden, files)?;
+        } else if file_type.is_file() {
+            let components: Vec<&str> = path
+                .components()
+                .map(|component| path_str(Path::new(component.as_os_str())))
+                .collect::<Result<_, Error>>()?;
+            files.push(components.join("/"));
+        }
+    }
+    Ok(())
+}
+
+impl Key {
+    /// Encrypts in place every file under `root` that this key does not
+    /// already own, after recording their metadata in the manifest
+    pub fn encrypt_tree(&self, root: &Path, include_hidden: bool) -> Result<TreeSummary, Error> {
+        let mut manifest = Manifest::read(root, self)?.unwrap_or_default();
+        let mut summary = TreeSummary::default();
+        let mut pending = Vec::new();
+        for relative in list_files(root, include_hidden)? {
+            if self.owns_file(path_str(&root.join(&relative))?)? {
+                summary.skipped.push(relative);
+                continue;
+            }
+            manifest.files.retain(|entry| entry.path != relative);
+            manifest.files.push(ManifestEntry::of(root, &relative)?);
+            pending.push(relative);
+        }
+        manifest.files.sort_by(|a, b| a.path.cmp(&b.path));
+        manifest.write(root, self)?;
+
+        for relative in pending {
+            let path = root.join(&relative);
+            let mut reader = BufReader::new(File::open(&path)?);
+            replace_file(path_str(&path)?, |file| {
+                let mut writer = EncryptingWriter::new(self, BufWriter::new(file))?;
+                io::copy(&mut reader, &mut writer)?;
+                writer.finish()?;
+                Ok(())
+            })?;
+            summary.processed.push(relative);
+        }
+        Ok(summary)
+    }
+    /// Decrypts in place the files of the manifest of `root`, restoring
+    /// their permissions and modification times, then deletes the manifest
+    ///
+    /// Files missing or not owned by this key are skipped, and the manifest
+    /// is rewritten with only their entries instead. Fails with
+    /// `Error::Integrity` on the first tampered file, which is left as is
+    /// along with the files after it.
+    pub fn decrypt_tree(&self, root: &Path) -> Result<TreeSummary, Error> {
+        let manifest = Manifest::read(root, self)?.ok_or_else(|| {
+            Error::decode(format!("no {} in {}", MANIFEST_FILENAME, root.display()))
+        })?;
+        let mut summary = TreeSummary::default();
+        for entry in &manifest.files {
+            let path = entry.path_in(root)?;
+            let filename = path_str(&path)?;
+            if !path.is_file() || !self.owns_file(filename)? {
+                summary.skipped.push(entry.path.clone());
+                continue;
+            }
+            if !self.verify_file(filename)? {
+                return Err(Error::Integrity);
+            }
+            let mut reader = DecryptingReader::new(self, armor::open_file(filename)?)?;
+            replace_file(filename, |file| {
+                let mut writer = BufWriter::new(file);
+                let size = io::copy(&mut reader, &mut writer)?;
+                writer.flush()?;
+                if size != entry.size {
+                    return Err(Error::decode(format!(
+                        "{} decrypts to {} bytes, the manifest says {}",
+                        entry.path, size, entry.size
+                    )));
+                }
+                Ok(())
+            })?;
+            entry.restore(root)?;
+            summary.processed.push(entry.path.clone());
+        }
+        if summary.skipped.is_empty() {
+            fs::remove_file(root.join(MANIFEST_FILENAME))?;
+        } else {
+            let remaining = Manifest {
+                files: manifest
+                    .files
+                    .iter()
+                    .filter(|entry| summary.skipped.contains(&entry.path))
+                    .cloned()
+                    .collect(),
+            };
+            remaining.write(root, self)?;
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aes256cbc::tree::{
+        list_files, restore_metadata, Manifest, ManifestEntry, MANIFEST_FILENAME,
+    };
+    use crate::aes256cbc::{Error, Key};
+    use k9::assert_equal;
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_decrypt_tree_restores_the_files_exactly() {
+        let root = std::env::temp_dir().join(format!("tree-{}", std::process::id()));
+        fs::create_dir_all(root.join("docs/notes")).unwrap();
+        fs::write(root.join("README.md"), b"# Project").unwrap();
+        fs::write(root.join("docs/notes/todo.txt"), b"").unwrap();
+        fs::write(root.join(".env"), b"SECRET=1").unwrap();
+        let modified = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+        fs::File::options()
+            .write(true)
+            .open(root.join("README.md"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(root.join("README.md"), fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let before = ManifestEntry::of(&root, "README.md").unwrap();
+
+        let key = Key::generate();
+        let summary = key.encrypt_tree(&root, false).unwrap();
+        assert_equal!(summary.processed, vec!["README.md", "docs/notes/todo.txt"]);
+        assert!(key
+            .owns_file(root.join("README.md").to_str().unwrap())
+            .unwrap());
+        assert_equal!(fs::read(root.join(".env")).unwrap(), b"SECRET=1");
+        assert_equal!(list_files(&root, true).unwrap().len(), 3);
+
+        let again = key.encrypt_tree(&root, false).unwrap();
+        assert!(again.processed.is_empty());
+        let manifest = Manifest::read(&root, &key).unwrap().unwrap();
+        assert_equal!(manifest.files[0], before);
+        assert!(matches!(
+            Key::generate().decrypt_tree(&root),
+            Err(Error::WrongKey)
+        ));
+
+        key.decrypt_tree(&root).unwrap();
+        assert_equal!(fs::read(root.join("README.md")).unwrap(), b"# Project");
+        assert_equal!(ManifestEntry::of(&root, "README.md").unwrap(), before);
+        assert!(!root.join(MANIFEST_FILENAME).exists());
+        fs::remove_dir_all(&root).unwrap();
+
+        let unsafe_entry = ManifestEntry {
+            path: String::from("../outside"),
+            ..before
+        };
+        assert!(matches!(
+            unsafe_entry.path_in(Path::new("/tmp")),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restore_metadata_without_owner_read() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("write-only-{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let modified = Duration::new(1_600_000_000, 0);
+        restore_metadata(&path, 0o200, modified).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_equal!(metadata.permissions().mode() & 0o7777, 0o200);
+        assert_equal!(metadata.modified().unwrap(), UNIX_EPOCH + modified);
+    }
+}